@@ -0,0 +1,279 @@
+use std::{
+    fmt::Display,
+    iter::Peekable,
+    sync::{Arc, Mutex},
+    vec::IntoIter,
+};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{expand::lookup_var, state::State, vars::check_name};
+
+#[derive(Debug, Clone, Copy)]
+pub enum UnaryOp {
+    Neg,
+    Plus,
+    Not,
+}
+
+impl UnaryOp {
+    pub fn from_symbol(symbol: &str) -> Self {
+        match symbol {
+            "-" => UnaryOp::Neg,
+            "+" => UnaryOp::Plus,
+            _ => UnaryOp::Not,
+        }
+    }
+
+    fn symbol(&self) -> &'static str {
+        match self {
+            UnaryOp::Neg => "-",
+            UnaryOp::Plus => "+",
+            UnaryOp::Not => "!",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum BinOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Le,
+    Ge,
+    Lt,
+    Gt,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+impl BinOp {
+    pub fn from_symbol(symbol: &str) -> Self {
+        match symbol {
+            "||" => BinOp::Or,
+            "&&" => BinOp::And,
+            "==" => BinOp::Eq,
+            "!=" => BinOp::Ne,
+            "<=" => BinOp::Le,
+            ">=" => BinOp::Ge,
+            "<" => BinOp::Lt,
+            ">" => BinOp::Gt,
+            "+" => BinOp::Add,
+            "-" => BinOp::Sub,
+            "*" => BinOp::Mul,
+            "/" => BinOp::Div,
+            _ => BinOp::Rem,
+        }
+    }
+
+    fn symbol(&self) -> &'static str {
+        match self {
+            BinOp::Or => "||",
+            BinOp::And => "&&",
+            BinOp::Eq => "==",
+            BinOp::Ne => "!=",
+            BinOp::Le => "<=",
+            BinOp::Ge => ">=",
+            BinOp::Lt => "<",
+            BinOp::Gt => ">",
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Rem => "%",
+        }
+    }
+
+    /// Higher binds tighter, as in C.
+    fn precedence(&self) -> u8 {
+        match self {
+            BinOp::Or => 1,
+            BinOp::And => 2,
+            BinOp::Eq | BinOp::Ne => 3,
+            BinOp::Le | BinOp::Ge | BinOp::Lt | BinOp::Gt => 4,
+            BinOp::Add | BinOp::Sub => 5,
+            BinOp::Mul | BinOp::Div | BinOp::Rem => 6,
+        }
+    }
+}
+
+/// An integer expression, as found in `$(( ))`, `let` and `math`.
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Num(i64),
+    /// A variable, with or without a `$` in front. Unset and empty ones are 0.
+    Var(String),
+    /// Parentheses the user wrote, kept so the expression displays as it was
+    /// typed.
+    Group(Box<Expr>),
+    Unary {
+        op: UnaryOp,
+        expr: Box<Expr>,
+    },
+    Binary {
+        op: BinOp,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    /// `name = expr`, which sets the variable and has its new value.
+    Assign {
+        name: String,
+        value: Box<Expr>,
+    },
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expr::Num(num) => num.fmt(f),
+            Expr::Var(name) if check_name(name).is_ok() => name.fmt(f),
+            Expr::Var(name) => write!(f, "${}", name),
+            Expr::Group(expr) => write!(f, "({})", expr),
+            Expr::Unary { op, expr } => write!(f, "{}{}", op.symbol(), expr),
+            Expr::Binary { op, left, right } => write!(f, "{} {} {}", left, op.symbol(), right),
+            Expr::Assign { name, value } => write!(f, "{} = {}", name, value),
+        }
+    }
+}
+
+/// Builds the tree for `first op operand op operand...`, binding tighter
+/// operators first. They're all left-associative.
+pub fn climb(first: Expr, rest: Vec<(BinOp, Expr)>) -> Expr {
+    climb_from(first, &mut rest.into_iter().peekable(), 0)
+}
+
+fn climb_from(mut left: Expr, rest: &mut Peekable<IntoIter<(BinOp, Expr)>>, min: u8) -> Expr {
+    while let Some(precedence) = rest.peek().map(|(op, _)| op.precedence()) {
+        if precedence < min {
+            break;
+        }
+        let (op, mut right) = rest.next().unwrap();
+        while let Some(next) = rest.peek().map(|(op, _)| op.precedence()) {
+            if next <= precedence {
+                break;
+            }
+            right = climb_from(right, rest, next);
+        }
+        left = Expr::Binary {
+            op,
+            left: Box::new(left),
+            right: Box::new(right),
+        };
+    }
+
+    left
+}
+
+fn overflow() -> anyhow::Error {
+    anyhow!("integer overflow")
+}
+
+impl Expr {
+    pub fn eval(&self, state: &Arc<Mutex<State>>) -> Result<i64> {
+        Ok(match self {
+            Expr::Num(num) => *num,
+            Expr::Var(name) => {
+                let value = lookup_var(name, state).unwrap_or_default();
+                let value = value.trim();
+                if value.is_empty() {
+                    return Ok(0);
+                }
+                value
+                    .parse()
+                    .map_err(|_| anyhow!("{}: {:?} is not an integer", name, value))?
+            }
+            Expr::Group(expr) => expr.eval(state)?,
+            Expr::Unary { op, expr } => {
+                let value = expr.eval(state)?;
+                match op {
+                    UnaryOp::Neg => value.checked_neg().ok_or_else(overflow)?,
+                    UnaryOp::Plus => value,
+                    UnaryOp::Not => (value == 0) as i64,
+                }
+            }
+            Expr::Binary { op, left, right } => {
+                let left = left.eval(state)?;
+                // Like C, the right side of && and || only runs if needed,
+                // which matters when it assigns.
+                match op {
+                    BinOp::Or if left != 0 => return Ok(1),
+                    BinOp::And if left == 0 => return Ok(0),
+                    _ => {}
+                }
+                let right = right.eval(state)?;
+                match op {
+                    BinOp::Or | BinOp::And => (right != 0) as i64,
+                    BinOp::Eq => (left == right) as i64,
+                    BinOp::Ne => (left != right) as i64,
+                    BinOp::Le => (left <= right) as i64,
+                    BinOp::Ge => (left >= right) as i64,
+                    BinOp::Lt => (left < right) as i64,
+                    BinOp::Gt => (left > right) as i64,
+                    BinOp::Add => left.checked_add(right).ok_or_else(overflow)?,
+                    BinOp::Sub => left.checked_sub(right).ok_or_else(overflow)?,
+                    BinOp::Mul => left.checked_mul(right).ok_or_else(overflow)?,
+                    BinOp::Div | BinOp::Rem if right == 0 => bail!("division by zero"),
+                    BinOp::Div => left.checked_div(right).ok_or_else(overflow)?,
+                    BinOp::Rem => left.checked_rem(right).ok_or_else(overflow)?,
+                }
+            }
+            Expr::Assign { name, value } => {
+                let value = value.eval(state)?;
+                state.lock().unwrap().vars.set(name, value.to_string())?;
+                value
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parser::parse_arith, shell::load_state};
+
+    async fn eval(text: &str) -> Result<i64> {
+        let state = load_state(false).await?;
+        parse_arith(text)?.eval(&state)
+    }
+
+    #[tokio::test]
+    async fn binds_tighter_operators_first() {
+        assert_eq!(eval("1 + 2 * 3").await.unwrap(), 7);
+        assert_eq!(eval("2 * 3 + 1").await.unwrap(), 7);
+        assert_eq!(eval("(1 + 2) * 3").await.unwrap(), 9);
+        assert_eq!(eval("1 + 2 < 4 && 2 == 2").await.unwrap(), 1);
+        assert_eq!(eval("1 || 0 && 0").await.unwrap(), 1);
+        assert_eq!(eval("1 + 2 * 3 - 4 / 2").await.unwrap(), 5);
+    }
+
+    #[tokio::test]
+    async fn associates_left() {
+        assert_eq!(eval("10 - 4 - 3").await.unwrap(), 3);
+        assert_eq!(eval("100 / 10 / 5").await.unwrap(), 2);
+        assert_eq!(eval("7 % 4 % 2").await.unwrap(), 1);
+        assert_eq!(eval("2 * 6 / 4 * 3").await.unwrap(), 9);
+    }
+
+    #[tokio::test]
+    async fn negates() {
+        assert_eq!(eval("-3 + 5").await.unwrap(), 2);
+        assert_eq!(eval("2 * -3").await.unwrap(), -6);
+        assert_eq!(eval("-(2 + 3) * 2").await.unwrap(), -10);
+        assert_eq!(eval("-(-4)").await.unwrap(), 4);
+        assert_eq!(eval("!0 + !5").await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn refuses_division_by_zero() {
+        for text in ["1 / 0", "5 % 0", "1 / (2 - 2)"] {
+            let e = eval(text).await.unwrap_err();
+            assert_eq!(e.to_string(), "division by zero", "{}", text);
+        }
+        // Only the side that's needed is evaluated.
+        assert_eq!(eval("0 && 1 / 0").await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,81 @@
+use std::{
+    io::Write,
+    ops::RangeInclusive,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use super::{Builtin, BuiltinIo};
+use crate::{command::CommandResult, parser::parse_arith, state::State};
+
+pub struct Let;
+
+#[async_trait]
+impl Builtin for Let {
+    fn name(&self) -> &'static str {
+        "let"
+    }
+
+    fn usage(&self) -> &'static str {
+        "expr..."
+    }
+
+    fn help(&self) -> &'static str {
+        "Evaluates each argument as an arithmetic expression, e.g. let x=x+1. Fails if the last one is 0."
+    }
+
+    fn arity(&self) -> RangeInclusive<usize> {
+        1..=usize::MAX
+    }
+
+    async fn run(
+        &self,
+        args: Vec<String>,
+        _io: &mut BuiltinIo,
+        state: &Arc<Mutex<State>>,
+    ) -> Result<CommandResult> {
+        let mut last = 0;
+        for arg in args {
+            last = parse_arith(&arg)?.eval(state)?;
+        }
+
+        Ok(CommandResult {
+            status: (last == 0) as i32,
+        })
+    }
+}
+
+pub struct Math;
+
+#[async_trait]
+impl Builtin for Math {
+    fn name(&self) -> &'static str {
+        "math"
+    }
+
+    fn usage(&self) -> &'static str {
+        "expr"
+    }
+
+    fn help(&self) -> &'static str {
+        "Prints the value of an arithmetic expression, which can be split across several arguments."
+    }
+
+    fn arity(&self) -> RangeInclusive<usize> {
+        1..=usize::MAX
+    }
+
+    async fn run(
+        &self,
+        args: Vec<String>,
+        io: &mut BuiltinIo,
+        state: &Arc<Mutex<State>>,
+    ) -> Result<CommandResult> {
+        let value = parse_arith(&args.join(" "))?.eval(state)?;
+        writeln!(io.stdout, "{}", value)?;
+
+        Ok(CommandResult { status: 0 })
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    collections::HashMap,
+    io::Write,
+    ops::RangeInclusive,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use protos::{
+    create_channel, env_client::EnvClient, sock_path_from_pid, status_client::StatusClient,
+    GetEnvRequest,
+};
+use tonic::Request;
+
+use super::{Builtin, BuiltinIo};
+use crate::{command::CommandResult, state::State};
+
+/// Fetches the exported variables of the psh with process id `pid`, and its
+/// unexported ones too if `include_locals` is set.
+async fn remote_env(pid: &str, include_locals: bool) -> Result<Vec<(String, String)>> {
+    let pid: u32 = pid.parse()?;
+    let sock_path = sock_path_from_pid(pid);
+
+    let channel = create_channel(sock_path).await?;
+
+    let mut client = EnvClient::new(channel);
+
+    let request = Request::new(GetEnvRequest { include_locals });
+
+    let resp = client.get_env(request).await?.into_inner();
+
+    Ok(resp
+        .vars
+        .into_iter()
+        .map(|var| (var.key, var.value))
+        .collect())
+}
+
+/// Strips a leading `-l`, which asks for the other shell's unexported
+/// variables as well.
+fn locals_flag(mut args: Vec<String>) -> (bool, Vec<String>) {
+    if args.first().map(String::as_str) == Some("-l") {
+        args.remove(0);
+        return (true, args);
+    }
+
+    (false, args)
+}
+
+pub struct CopyEnv;
+
+#[async_trait]
+impl Builtin for CopyEnv {
+    fn name(&self) -> &'static str {
+        "copyenv"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[-l] pid var"
+    }
+
+    fn help(&self) -> &'static str {
+        "Copies and exports a variable from another psh's environment, or from all its variables with -l."
+    }
+
+    fn arity(&self) -> RangeInclusive<usize> {
+        2..=3
+    }
+
+    async fn run(
+        &self,
+        args: Vec<String>,
+        _io: &mut BuiltinIo,
+        state: &Arc<Mutex<State>>,
+    ) -> Result<CommandResult> {
+        let (include_locals, args) = locals_flag(args);
+        let [pid, key] = args.as_slice() else {
+            anyhow::bail!("usage: {} {}", self.name(), self.usage());
+        };
+        let vars = remote_env(pid, include_locals).await?;
+        let value = vars.into_iter().find(|(k, _)| k == key).map(|(_, v)| v);
+
+        state
+            .lock()
+            .unwrap()
+            .vars
+            .export(key, Some(value.unwrap_or_default()))?;
+
+        Ok(CommandResult { status: 0 })
+    }
+}
+
+pub struct DiffEnv;
+
+#[async_trait]
+impl Builtin for DiffEnv {
+    fn name(&self) -> &'static str {
+        "diffenv"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[-l] pid"
+    }
+
+    fn help(&self) -> &'static str {
+        "Shows the exported variables another psh has set differently or that this one lacks, or all variables with -l."
+    }
+
+    fn arity(&self) -> RangeInclusive<usize> {
+        1..=2
+    }
+
+    async fn run(
+        &self,
+        args: Vec<String>,
+        io: &mut BuiltinIo,
+        state: &Arc<Mutex<State>>,
+    ) -> Result<CommandResult> {
+        let (include_locals, args) = locals_flag(args);
+        let [pid] = args.as_slice() else {
+            anyhow::bail!("usage: {} {}", self.name(), self.usage());
+        };
+        let remote_vars = remote_env(pid, include_locals).await?;
+
+        let local_vars = state
+            .lock()
+            .unwrap()
+            .vars
+            .iter()
+            .filter(|(_, var)| include_locals || var.exported)
+            .map(|(key, var)| (key.clone(), var.value.clone()))
+            .collect::<HashMap<_, _>>();
+
+        for (key, value) in remote_vars {
+            let local = local_vars.get(&key);
+
+            if let Some(local) = local {
+                if *local != value {
+                    writeln!(io.stdout, " {}:", key)?;
+                    writeln!(io.stdout, "\tLocal:  {}", local)?;
+                    writeln!(io.stdout, "\tTheirs: {}", value)?;
+                }
+            } else {
+                writeln!(io.stdout, "+{}: {}", key, value)?;
+            }
+        }
+
+        Ok(CommandResult { status: 0 })
+    }
+}
+
+pub struct Pshl;
+
+#[async_trait]
+impl Builtin for Pshl {
+    fn name(&self) -> &'static str {
+        "pshl"
+    }
+
+    fn usage(&self) -> &'static str {
+        ""
+    }
+
+    fn help(&self) -> &'static str {
+        "Lists the other running psh instances and what they're doing."
+    }
+
+    fn arity(&self) -> RangeInclusive<usize> {
+        0..=0
+    }
+
+    async fn run(
+        &self,
+        _args: Vec<String>,
+        io: &mut BuiltinIo,
+        _state: &Arc<Mutex<State>>,
+    ) -> Result<CommandResult> {
+        let psh_path = PathBuf::from("/tmp/psh");
+
+        for dir in std::fs::read_dir(psh_path)?.flatten() {
+            if let Ok(pid) = dir.file_name().to_string_lossy().parse::<u32>() {
+                if pid == std::process::id() {
+                    continue;
+                }
+                let sock_path = sock_path_from_pid(pid);
+                if let Ok(channel) = create_channel(sock_path).await {
+                    let mut client = StatusClient::new(channel);
+
+                    let request = Request::new(());
+
+                    if let Ok(resp) = client.get_status(request).await {
+                        let resp = resp.into_inner();
+                        writeln!(
+                            io.stdout,
+                            "{}: {} ({})",
+                            dir.file_name().to_string_lossy(),
+                            resp.current_command,
+                            resp.working_dir
+                        )?;
+                    }
+                }
+            }
+        }
+
+        Ok(CommandResult { status: 0 })
+    }
+}
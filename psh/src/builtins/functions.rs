@@ -0,0 +1,101 @@
+use std::{
+    io::Write,
+    ops::RangeInclusive,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+
+use super::{Builtin, BuiltinIo};
+use crate::{command::CommandResult, state::State};
+
+pub struct ReturnBuiltin;
+
+#[async_trait]
+impl Builtin for ReturnBuiltin {
+    fn name(&self) -> &'static str {
+        "return"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[status]"
+    }
+
+    fn help(&self) -> &'static str {
+        "Ends the current function or sourced script, with the given status or that of the last command."
+    }
+
+    fn arity(&self) -> RangeInclusive<usize> {
+        0..=1
+    }
+
+    async fn run(
+        &self,
+        args: Vec<String>,
+        _io: &mut BuiltinIo,
+        state: &Arc<Mutex<State>>,
+    ) -> Result<CommandResult> {
+        let mut state = state.lock().unwrap();
+        if state.function_depth == 0 {
+            bail!("can only return from a function or sourced script");
+        }
+        let status = match args.first() {
+            Some(status) => status
+                .parse()
+                .map_err(|_| anyhow::anyhow!("{}: numeric argument required", status))?,
+            None => state.last_status,
+        };
+        state.returning = Some(status);
+
+        Ok(CommandResult { status })
+    }
+}
+
+pub struct Functions;
+
+#[async_trait]
+impl Builtin for Functions {
+    fn name(&self) -> &'static str {
+        "functions"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[name...]"
+    }
+
+    fn help(&self) -> &'static str {
+        "Shows the definitions of the given functions, or of all of them."
+    }
+
+    async fn run(
+        &self,
+        args: Vec<String>,
+        io: &mut BuiltinIo,
+        state: &Arc<Mutex<State>>,
+    ) -> Result<CommandResult> {
+        let functions = state.lock().unwrap().functions.clone();
+
+        if args.is_empty() {
+            let mut functions = functions.into_values().collect::<Vec<_>>();
+            functions.sort_by(|a, b| a.name.cmp(&b.name));
+            for function in functions {
+                writeln!(io.stdout, "{}", function.source)?;
+            }
+            return Ok(CommandResult { status: 0 });
+        }
+
+        let mut status = 0;
+        for name in args {
+            match functions.get(&name) {
+                Some(function) => writeln!(io.stdout, "{}", function.source)?,
+                None => {
+                    writeln!(io.stderr, "functions: {}: not found", name)?;
+                    status = 1;
+                }
+            }
+        }
+
+        Ok(CommandResult { status })
+    }
+}
@@ -0,0 +1,246 @@
+use std::{
+    io::Write,
+    ops::RangeInclusive,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use super::{Builtin, BuiltinIo};
+use crate::{
+    command::CommandResult,
+    job::{wait_for_job, SignalCounts, INTERRUPTED_STATUS},
+    state::State,
+};
+
+/// Parses a job spec like `%2` or `2`. `%`, `%%` and `%+` (and no spec at
+/// all) mean the most recent job, which is `None`.
+fn job_id(spec: Option<&String>) -> Result<Option<usize>> {
+    let Some(spec) = spec else {
+        return Ok(None);
+    };
+    let spec = spec.trim_start_matches('%');
+    if spec.is_empty() || spec == "%" || spec == "+" {
+        return Ok(None);
+    }
+
+    Ok(Some(
+        spec.parse()
+            .map_err(|_| anyhow::anyhow!("{}: no such job", spec))?,
+    ))
+}
+
+pub struct Jobs;
+
+#[async_trait]
+impl Builtin for Jobs {
+    fn name(&self) -> &'static str {
+        "jobs"
+    }
+
+    fn usage(&self) -> &'static str {
+        ""
+    }
+
+    fn help(&self) -> &'static str {
+        "Lists background and stopped jobs."
+    }
+
+    fn arity(&self) -> RangeInclusive<usize> {
+        0..=0
+    }
+
+    async fn run(
+        &self,
+        _args: Vec<String>,
+        io: &mut BuiltinIo,
+        state: &Arc<Mutex<State>>,
+    ) -> Result<CommandResult> {
+        let mut state = state.lock().unwrap();
+        for job in state.jobs.iter_mut() {
+            if let Err(e) = job.update() {
+                writeln!(io.stderr, "Couldn't check on job {}: {}", job.id, e)?;
+            }
+            job.notified = true;
+            writeln!(io.stdout, "{}", job)?;
+        }
+        state.jobs.retain(|job| !job.is_completed());
+
+        Ok(CommandResult { status: 0 })
+    }
+}
+
+pub struct Fg;
+
+#[async_trait]
+impl Builtin for Fg {
+    fn name(&self) -> &'static str {
+        "fg"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[%job]"
+    }
+
+    fn help(&self) -> &'static str {
+        "Brings a job to the foreground, resuming it if it's stopped."
+    }
+
+    fn arity(&self) -> RangeInclusive<usize> {
+        0..=1
+    }
+
+    async fn run(
+        &self,
+        args: Vec<String>,
+        io: &mut BuiltinIo,
+        state: &Arc<Mutex<State>>,
+    ) -> Result<CommandResult> {
+        let id = job_id(args.first())?;
+        if !state.lock().unwrap().interactive {
+            writeln!(io.stderr, "fg: no job control")?;
+            return Ok(CommandResult { status: 1 });
+        }
+        let job = state.lock().unwrap().take_job(id);
+        let Some(job) = job else {
+            writeln!(io.stderr, "fg: no such job")?;
+            return Ok(CommandResult { status: 1 });
+        };
+        writeln!(io.stdout, "{}", job.command)?;
+
+        let statuses = wait_for_job(job, true, state).await?;
+
+        Ok(CommandResult {
+            status: state.lock().unwrap().record_statuses(statuses),
+        })
+    }
+}
+
+pub struct Bg;
+
+#[async_trait]
+impl Builtin for Bg {
+    fn name(&self) -> &'static str {
+        "bg"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[%job]"
+    }
+
+    fn help(&self) -> &'static str {
+        "Resumes a stopped job in the background."
+    }
+
+    fn arity(&self) -> RangeInclusive<usize> {
+        0..=1
+    }
+
+    async fn run(
+        &self,
+        args: Vec<String>,
+        io: &mut BuiltinIo,
+        state: &Arc<Mutex<State>>,
+    ) -> Result<CommandResult> {
+        let id = job_id(args.first())?;
+        let mut state = state.lock().unwrap();
+        if !state.interactive {
+            writeln!(io.stderr, "bg: no job control")?;
+            return Ok(CommandResult { status: 1 });
+        }
+        let job = match id {
+            Some(id) => state.jobs.iter_mut().find(|job| job.id == id),
+            None => state.jobs.last_mut(),
+        };
+        let Some(job) = job else {
+            writeln!(io.stderr, "bg: no such job")?;
+            return Ok(CommandResult { status: 1 });
+        };
+        job.resume()?;
+        writeln!(io.stdout, "[{}] {} &", job.id, job.command)?;
+
+        Ok(CommandResult { status: 0 })
+    }
+}
+
+pub struct Wait;
+
+#[async_trait]
+impl Builtin for Wait {
+    fn name(&self) -> &'static str {
+        "wait"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[%job]"
+    }
+
+    fn help(&self) -> &'static str {
+        "Waits for a job, or every job, to finish and returns its exit code."
+    }
+
+    fn arity(&self) -> RangeInclusive<usize> {
+        0..=1
+    }
+
+    async fn run(
+        &self,
+        args: Vec<String>,
+        io: &mut BuiltinIo,
+        state: &Arc<Mutex<State>>,
+    ) -> Result<CommandResult> {
+        let id = job_id(args.first())?;
+        let ids = match id {
+            Some(id) => vec![id],
+            None => state
+                .lock()
+                .unwrap()
+                .jobs
+                .iter()
+                .map(|job| job.id)
+                .collect(),
+        };
+
+        let mut status = 0;
+        for id in ids {
+            let job = state.lock().unwrap().take_job(Some(id));
+            let Some(mut job) = job else {
+                writeln!(io.stderr, "wait: no such job: {}", id)?;
+                status = 127;
+                continue;
+            };
+            // Unlike a foreground job, nothing else gets Ctrl-C, so it
+            // stops the waiting instead.
+            let signals = state.lock().unwrap().signals.clone();
+            let interrupted = match signals.clone() {
+                Some(SignalCounts { mut interrupts, .. }) => {
+                    interrupts.borrow_and_update();
+                    tokio::select! {
+                        waited = job.wait(signals) => {
+                            waited?;
+                            false
+                        }
+                        _ = interrupts.changed() => true,
+                    }
+                }
+                None => {
+                    job.wait(None).await?;
+                    false
+                }
+            };
+            if interrupted {
+                state.lock().unwrap().add_job(job);
+                return Ok(CommandResult {
+                    status: INTERRUPTED_STATUS,
+                });
+            }
+            status = job.status();
+            if job.is_stopped() {
+                state.lock().unwrap().add_job(job);
+            }
+        }
+
+        Ok(CommandResult { status })
+    }
+}
@@ -0,0 +1,99 @@
+use std::{
+    ops::RangeInclusive,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+
+use super::{Builtin, BuiltinIo};
+use crate::{
+    command::{CommandResult, LoopJump},
+    state::State,
+};
+
+/// Sets the shell to leave `n` loops, or the innermost if there's no `n`.
+fn jump(args: &[String], continuing: bool, state: &Arc<Mutex<State>>) -> Result<()> {
+    let levels = match args.first() {
+        Some(n) => match n.parse::<usize>() {
+            Ok(0) | Err(_) => bail!("{}: loop count out of range", n),
+            Ok(levels) => levels,
+        },
+        None => 1,
+    };
+    let mut state = state.lock().unwrap();
+    if state.loop_depth == 0 {
+        bail!("only meaningful in a `for`, `while` or `until` loop");
+    }
+    state.jumping = Some(LoopJump {
+        // Asking to leave more loops than there are leaves all of them.
+        levels: levels.min(state.loop_depth),
+        continuing,
+    });
+
+    Ok(())
+}
+
+pub struct Break;
+
+#[async_trait]
+impl Builtin for Break {
+    fn name(&self) -> &'static str {
+        "break"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[n]"
+    }
+
+    fn help(&self) -> &'static str {
+        "Leaves the innermost loop, or the n innermost loops."
+    }
+
+    fn arity(&self) -> RangeInclusive<usize> {
+        0..=1
+    }
+
+    async fn run(
+        &self,
+        args: Vec<String>,
+        _io: &mut BuiltinIo,
+        state: &Arc<Mutex<State>>,
+    ) -> Result<CommandResult> {
+        jump(&args, false, state)?;
+
+        Ok(CommandResult { status: 0 })
+    }
+}
+
+pub struct Continue;
+
+#[async_trait]
+impl Builtin for Continue {
+    fn name(&self) -> &'static str {
+        "continue"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[n]"
+    }
+
+    fn help(&self) -> &'static str {
+        "Skips to the next pass of the innermost loop, or of the nth one out."
+    }
+
+    fn arity(&self) -> RangeInclusive<usize> {
+        0..=1
+    }
+
+    async fn run(
+        &self,
+        args: Vec<String>,
+        _io: &mut BuiltinIo,
+        state: &Arc<Mutex<State>>,
+    ) -> Result<CommandResult> {
+        jump(&args, true, state)?;
+
+        Ok(CommandResult { status: 0 })
+    }
+}
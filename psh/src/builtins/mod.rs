@@ -0,0 +1,220 @@
+mod arith;
+mod env;
+mod functions;
+mod jobs;
+mod loops;
+mod shell;
+mod vars;
+
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    io::Write,
+    ops::RangeInclusive,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::{
+    command::CommandResult,
+    redirect::{Io, Output, Stdios},
+    state::State,
+};
+
+/// Exit code for a builtin that was called with the wrong arguments.
+const USAGE_STATUS: i32 = 2;
+
+/// A command psh runs itself rather than looking for a program.
+#[async_trait]
+pub trait Builtin: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// The arguments it takes, e.g. `[dir]`.
+    fn usage(&self) -> &'static str;
+
+    /// A sentence or two about what it does, shown by `help`.
+    fn help(&self) -> &'static str;
+
+    /// How many arguments it accepts. Anything else is a usage error.
+    fn arity(&self) -> RangeInclusive<usize> {
+        0..=usize::MAX
+    }
+
+    async fn run(
+        &self,
+        args: Vec<String>,
+        io: &mut BuiltinIo,
+        state: &Arc<Mutex<State>>,
+    ) -> Result<CommandResult>;
+}
+
+/// The streams a builtin uses in place of its own stdin, stdout and stderr.
+pub struct BuiltinIo {
+    pub stdin: Io,
+    pub stdout: Output,
+    pub stderr: Output,
+}
+
+impl BuiltinIo {
+    pub fn new(stdios: Stdios) -> Result<Self> {
+        Ok(Self {
+            stdin: stdios.stdin,
+            stdout: Output::new(stdios.stdout, 1)?,
+            stderr: Output::new(stdios.stderr, 2)?,
+        })
+    }
+
+    pub fn finish(mut self) {
+        let _ = self.stdout.flush();
+        self.stdout.finish();
+        self.stderr.finish();
+    }
+}
+
+/// Every builtin the shell knows about, by name.
+#[derive(Clone, Default)]
+pub struct Builtins {
+    builtins: BTreeMap<&'static str, Arc<dyn Builtin>>,
+}
+
+impl Builtins {
+    /// A registry holding all of psh's own builtins.
+    pub fn new() -> Self {
+        let mut builtins = Self::default();
+        builtins.register(arith::Let);
+        builtins.register(arith::Math);
+        builtins.register(env::CopyEnv);
+        builtins.register(env::DiffEnv);
+        builtins.register(env::Pshl);
+        builtins.register(functions::ReturnBuiltin);
+        builtins.register(functions::Functions);
+        builtins.register(jobs::Jobs);
+        builtins.register(jobs::Fg);
+        builtins.register(jobs::Bg);
+        builtins.register(jobs::Wait);
+        builtins.register(loops::Break);
+        builtins.register(loops::Continue);
+        builtins.register(shell::Cd);
+        builtins.register(shell::Set);
+        builtins.register(shell::AliasBuiltin);
+        builtins.register(shell::Exit);
+        builtins.register(shell::Source { name: "source" });
+        builtins.register(shell::Source { name: "." });
+        builtins.register(vars::Export);
+        builtins.register(vars::Unset);
+        builtins.register(vars::Local);
+        builtins.register(vars::Readonly);
+        builtins.register(Help);
+
+        builtins
+    }
+
+    pub fn register(&mut self, builtin: impl Builtin + 'static) {
+        self.builtins.insert(builtin.name(), Arc::new(builtin));
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn Builtin>> {
+        self.builtins.get(name).cloned()
+    }
+
+    /// Builtins in alphabetical order.
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<dyn Builtin>> {
+        self.builtins.values()
+    }
+}
+
+impl Debug for Builtins {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.builtins.keys()).finish()
+    }
+}
+
+/// The builtin's name followed by its usage, e.g. `cd [dir]`.
+fn synopsis(builtin: &dyn Builtin) -> String {
+    format!("{} {}", builtin.name(), builtin.usage())
+        .trim_end()
+        .to_owned()
+}
+
+/// Runs `builtin`, reporting usage errors and failures on its stderr rather
+/// than to the caller.
+pub async fn run(
+    builtin: &dyn Builtin,
+    args: Vec<String>,
+    io: &mut BuiltinIo,
+    state: &Arc<Mutex<State>>,
+) -> CommandResult {
+    if !builtin.arity().contains(&args.len()) {
+        let _ = writeln!(
+            io.stderr,
+            "{}: usage: {}",
+            builtin.name(),
+            synopsis(builtin)
+        );
+        return CommandResult {
+            status: USAGE_STATUS,
+        };
+    }
+
+    match builtin.run(args, io, state).await {
+        Ok(result) => result,
+        Err(e) => {
+            let _ = writeln!(io.stderr, "{}: {}", builtin.name(), e);
+            CommandResult { status: 1 }
+        }
+    }
+}
+
+struct Help;
+
+#[async_trait]
+impl Builtin for Help {
+    fn name(&self) -> &'static str {
+        "help"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[builtin]"
+    }
+
+    fn help(&self) -> &'static str {
+        "Lists the builtins, or describes one of them."
+    }
+
+    fn arity(&self) -> RangeInclusive<usize> {
+        0..=1
+    }
+
+    async fn run(
+        &self,
+        args: Vec<String>,
+        io: &mut BuiltinIo,
+        state: &Arc<Mutex<State>>,
+    ) -> Result<CommandResult> {
+        let builtins = state.lock().unwrap().builtins.clone();
+
+        if let Some(name) = args.first() {
+            let Some(builtin) = builtins.get(name) else {
+                writeln!(io.stderr, "help: no builtin named {}", name)?;
+                return Ok(CommandResult { status: 1 });
+            };
+            writeln!(io.stdout, "{}", synopsis(builtin.as_ref()))?;
+            writeln!(io.stdout, "    {}", builtin.help())?;
+
+            return Ok(CommandResult { status: 0 });
+        }
+
+        for builtin in builtins.iter() {
+            writeln!(
+                io.stdout,
+                "{:<34} {}",
+                synopsis(builtin.as_ref()),
+                builtin.help()
+            )?;
+        }
+
+        Ok(CommandResult { status: 0 })
+    }
+}
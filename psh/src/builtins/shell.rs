@@ -0,0 +1,283 @@
+use std::{
+    env, fs,
+    io::Write,
+    ops::RangeInclusive,
+    path::Path,
+    process::exit,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use super::{Builtin, BuiltinIo};
+use crate::{
+    command::{replace_stderr, restore_stderr, Arg, CommandResult, LoopJump, MAX_FUNCTION_DEPTH},
+    shell::run_script,
+    state::{Alias, NoMatch, State},
+};
+
+pub struct Cd;
+
+#[async_trait]
+impl Builtin for Cd {
+    fn name(&self) -> &'static str {
+        "cd"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[dir]"
+    }
+
+    fn help(&self) -> &'static str {
+        "Changes the working directory, to $HOME if none is given."
+    }
+
+    fn arity(&self) -> RangeInclusive<usize> {
+        0..=1
+    }
+
+    async fn run(
+        &self,
+        args: Vec<String>,
+        io: &mut BuiltinIo,
+        state: &Arc<Mutex<State>>,
+    ) -> Result<CommandResult> {
+        let home = state.lock().unwrap().vars.get("HOME").map(str::to_owned);
+        let new_directory = args
+            .first()
+            .cloned()
+            .or(home)
+            .unwrap_or_else(|| String::from("/"));
+        let newpath = Path::new(&new_directory);
+        if let Err(e) = env::set_current_dir(newpath) {
+            writeln!(io.stderr, "cd: {}: {}", new_directory, e)?;
+            return Ok(CommandResult { status: 1 });
+        }
+
+        Ok(CommandResult { status: 0 })
+    }
+}
+
+pub struct Set;
+
+#[async_trait]
+impl Builtin for Set {
+    fn name(&self) -> &'static str {
+        "set"
+    }
+
+    fn usage(&self) -> &'static str {
+        "key value | -o/+o option"
+    }
+
+    fn help(&self) -> &'static str {
+        "Sets and exports a variable, or turns a shell option (pipefail, nullglob or failglob) on with -o or off with +o."
+    }
+
+    fn arity(&self) -> RangeInclusive<usize> {
+        2..=2
+    }
+
+    async fn run(
+        &self,
+        args: Vec<String>,
+        io: &mut BuiltinIo,
+        state: &Arc<Mutex<State>>,
+    ) -> Result<CommandResult> {
+        let (key, value) = (&args[0], &args[1]);
+        if key == "-o" || key == "+o" {
+            let enable = key == "-o";
+            let mut state = state.lock().unwrap();
+            let options = &mut state.options;
+            match value.as_str() {
+                "pipefail" => options.pipefail = enable,
+                "nullglob" | "failglob" => {
+                    let nomatch = match value.as_str() {
+                        "nullglob" => NoMatch::Empty,
+                        _ => NoMatch::Error,
+                    };
+                    if enable {
+                        options.nomatch = nomatch;
+                    } else if options.nomatch == nomatch {
+                        options.nomatch = NoMatch::PassThrough;
+                    }
+                }
+                _ => {
+                    writeln!(io.stderr, "set: {}: invalid option name", value)?;
+                    return Ok(CommandResult { status: 1 });
+                }
+            }
+            return Ok(CommandResult { status: 0 });
+        }
+
+        state
+            .lock()
+            .unwrap()
+            .vars
+            .export(key, Some(value.trim().to_owned()))?;
+
+        Ok(CommandResult { status: 0 })
+    }
+}
+
+pub struct AliasBuiltin;
+
+#[async_trait]
+impl Builtin for AliasBuiltin {
+    fn name(&self) -> &'static str {
+        "alias"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[name command [args...]]"
+    }
+
+    fn help(&self) -> &'static str {
+        "Defines an alias, or lists them all when given no definition."
+    }
+
+    async fn run(
+        &self,
+        args: Vec<String>,
+        io: &mut BuiltinIo,
+        state: &Arc<Mutex<State>>,
+    ) -> Result<CommandResult> {
+        if args.len() < 2 {
+            let aliases = state.lock().unwrap().aliases.clone();
+            for (_, alias) in aliases.iter() {
+                writeln!(io.stdout, "{}", alias.display())?;
+            }
+            return Ok(CommandResult { status: 0 });
+        }
+        let mut args = args.into_iter();
+        let alias = args.next().unwrap();
+        let command = args.next().unwrap();
+        let args = args.map(|arg_string| Arg::String { arg_string }).collect();
+
+        let aliasdef = Alias {
+            alias: alias.to_owned(),
+            command,
+            args,
+        };
+
+        state.lock().unwrap().aliases.insert(alias, aliasdef);
+
+        Ok(CommandResult { status: 0 })
+    }
+}
+
+pub struct Exit;
+
+#[async_trait]
+impl Builtin for Exit {
+    fn name(&self) -> &'static str {
+        "exit"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[status]"
+    }
+
+    fn help(&self) -> &'static str {
+        "Exits the shell."
+    }
+
+    fn arity(&self) -> RangeInclusive<usize> {
+        0..=1
+    }
+
+    async fn run(
+        &self,
+        args: Vec<String>,
+        io: &mut BuiltinIo,
+        _state: &Arc<Mutex<State>>,
+    ) -> Result<CommandResult> {
+        let status = match args.first() {
+            Some(status) => status
+                .parse()
+                .map_err(|_| anyhow::anyhow!("{}: numeric argument required", status))?,
+            None => 0,
+        };
+        let _ = io.stdout.flush();
+
+        exit(status);
+    }
+}
+
+/// `source`, and its shorter name `.`.
+pub struct Source {
+    pub name: &'static str,
+}
+
+#[async_trait]
+impl Builtin for Source {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn usage(&self) -> &'static str {
+        "file [args...]"
+    }
+
+    fn help(&self) -> &'static str {
+        "Runs the commands in a file in the current shell, with any arguments as $1 onwards."
+    }
+
+    fn arity(&self) -> RangeInclusive<usize> {
+        1..=usize::MAX
+    }
+
+    async fn run(
+        &self,
+        args: Vec<String>,
+        io: &mut BuiltinIo,
+        state: &Arc<Mutex<State>>,
+    ) -> Result<CommandResult> {
+        let mut args = args.into_iter();
+        let path = args.next().unwrap();
+        let args = args.collect::<Vec<_>>();
+        let script = fs::read_to_string(&path).map_err(|e| anyhow::anyhow!("{}: {}", path, e))?;
+        let _ = io.stdout.flush();
+        let _ = io.stderr.flush();
+        let stdin = io.stdin.try_clone()?;
+        let stdout = io.stdout.try_clone_io()?;
+        let stderr = io.stderr.try_clone_io()?;
+
+        let saved_positional = {
+            let mut state = state.lock().unwrap();
+            if state.function_depth >= MAX_FUNCTION_DEPTH {
+                anyhow::bail!("{}: maximum nesting level exceeded", path);
+            }
+            state.function_depth += 1;
+            // Without arguments, the script sees the caller's.
+            (!args.is_empty()).then(|| std::mem::replace(&mut state.positional, args))
+        };
+
+        // The shell goes back to how it was even if this fails.
+        let result = match replace_stderr(stderr) {
+            Ok(saved_stderr) => {
+                let result = run_script(&script, &path, stdin, stdout, state).await;
+                restore_stderr(saved_stderr).and(result)
+            }
+            Err(e) => Err(e),
+        };
+
+        {
+            let mut state = state.lock().unwrap();
+            if let Some(positional) = saved_positional {
+                state.positional = positional;
+            }
+            state.function_depth -= 1;
+            // Hand `break` or `continue` on to the loop `source` ran in.
+            if let Err(e) = &result {
+                if let Some(jump) = e.downcast_ref::<LoopJump>() {
+                    state.jumping = Some(*jump);
+                    return Ok(CommandResult { status: 0 });
+                }
+            }
+        }
+
+        Ok(CommandResult { status: result? })
+    }
+}
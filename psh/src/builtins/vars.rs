@@ -0,0 +1,180 @@
+use std::{
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use super::{Builtin, BuiltinIo};
+use crate::{command::CommandResult, state::State, vars::check_name};
+
+/// Splits `NAME=value` into its name and value, or just the name if there's
+/// no `=`.
+fn name_value(arg: &str) -> (&str, Option<String>) {
+    match arg.split_once('=') {
+        Some((name, value)) => (name, Some(value.to_owned())),
+        None => (arg, None),
+    }
+}
+
+/// Applies `f` to every argument, reporting failures on stderr and carrying
+/// on with the rest.
+fn for_each_var(
+    args: &[String],
+    io: &mut BuiltinIo,
+    mut f: impl FnMut(&str, Option<String>) -> Result<()>,
+) -> Result<CommandResult> {
+    let mut status = 0;
+    for arg in args {
+        let (name, value) = name_value(arg);
+        if let Err(e) = check_name(name).and_then(|_| f(name, value)) {
+            writeln!(io.stderr, "{}", e)?;
+            status = 1;
+        }
+    }
+
+    Ok(CommandResult { status })
+}
+
+pub struct Export;
+
+#[async_trait]
+impl Builtin for Export {
+    fn name(&self) -> &'static str {
+        "export"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[-n] [name[=value]...]"
+    }
+
+    fn help(&self) -> &'static str {
+        "Passes variables on to commands the shell runs, or stops passing them with -n. Lists exported variables when given none."
+    }
+
+    async fn run(
+        &self,
+        args: Vec<String>,
+        io: &mut BuiltinIo,
+        state: &Arc<Mutex<State>>,
+    ) -> Result<CommandResult> {
+        let (unexport, args) = match args.split_first() {
+            Some((flag, rest)) if flag == "-n" => (true, rest),
+            _ => (false, args.as_slice()),
+        };
+
+        if args.is_empty() && !unexport {
+            for (name, value) in state.lock().unwrap().vars.exported() {
+                writeln!(io.stdout, "export {}={:?}", name, value)?;
+            }
+            return Ok(CommandResult { status: 0 });
+        }
+
+        let mut state = state.lock().unwrap();
+        for_each_var(args, io, |name, value| {
+            if unexport {
+                if let Some(value) = value {
+                    state.vars.set(name, value)?;
+                }
+                state.vars.unexport(name);
+                Ok(())
+            } else {
+                state.vars.export(name, value)
+            }
+        })
+    }
+}
+
+pub struct Unset;
+
+#[async_trait]
+impl Builtin for Unset {
+    fn name(&self) -> &'static str {
+        "unset"
+    }
+
+    fn usage(&self) -> &'static str {
+        "name..."
+    }
+
+    fn help(&self) -> &'static str {
+        "Removes variables."
+    }
+
+    async fn run(
+        &self,
+        args: Vec<String>,
+        io: &mut BuiltinIo,
+        state: &Arc<Mutex<State>>,
+    ) -> Result<CommandResult> {
+        let mut state = state.lock().unwrap();
+        for_each_var(&args, io, |name, _| state.vars.unset(name))
+    }
+}
+
+pub struct Local;
+
+#[async_trait]
+impl Builtin for Local {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    fn usage(&self) -> &'static str {
+        "name[=value]..."
+    }
+
+    fn help(&self) -> &'static str {
+        "Declares variables that only last until the current scope ends."
+    }
+
+    async fn run(
+        &self,
+        args: Vec<String>,
+        io: &mut BuiltinIo,
+        state: &Arc<Mutex<State>>,
+    ) -> Result<CommandResult> {
+        let mut state = state.lock().unwrap();
+        for_each_var(&args, io, |name, value| {
+            state.vars.declare_local(name, value)
+        })
+    }
+}
+
+pub struct Readonly;
+
+#[async_trait]
+impl Builtin for Readonly {
+    fn name(&self) -> &'static str {
+        "readonly"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[name[=value]...]"
+    }
+
+    fn help(&self) -> &'static str {
+        "Stops variables from being changed or unset. Lists read-only variables when given none."
+    }
+
+    async fn run(
+        &self,
+        args: Vec<String>,
+        io: &mut BuiltinIo,
+        state: &Arc<Mutex<State>>,
+    ) -> Result<CommandResult> {
+        let mut state = state.lock().unwrap();
+
+        if args.is_empty() {
+            for (name, var) in state.vars.iter().filter(|(_, var)| var.readonly) {
+                writeln!(io.stdout, "readonly {}={:?}", name, var.value)?;
+            }
+            return Ok(CommandResult { status: 0 });
+        }
+
+        for_each_var(&args, io, |name, value| {
+            state.vars.make_readonly(name, value)
+        })
+    }
+}
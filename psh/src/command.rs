@@ -1,226 +1,105 @@
 use anyhow::{bail, Result};
 use async_recursion::async_recursion;
-use protos::{
-    create_channel, env_client::EnvClient, sock_path_from_pid, status_client::StatusClient,
+use nix::{
+    fcntl::{fcntl, FcntlArg},
+    unistd::{close, dup2, Pid},
 };
 use std::{
-    collections::HashMap,
-    env,
     fmt::Display,
-    fs::File,
-    path::{Path, PathBuf},
-    process::{exit, Child, Command as OsCommand, Stdio},
+    io::{ErrorKind, Read},
+    os::unix::{io::RawFd, process::CommandExt},
+    process::Command as OsCommand,
     sync::{Arc, Mutex},
+    thread,
 };
-use tonic::Request;
 
-use crate::state::{Alias, State};
-
-async fn run_builtin(
-    command: &Command,
-    state: &Arc<Mutex<State>>,
-) -> Result<Option<CommandResult>> {
-    Ok(match command {
-        Command::Simple { command, args } => match command.as_str() {
-            "copyenv" => {
-                if args.len() != 2 {
-                    eprintln!("copyenv takes a pid and a var name");
-                    return Ok(Some(CommandResult { output: None }));
-                }
-                let mut args = args.iter();
-                let arg = args.next().unwrap();
-                let pid = eval_arg(&arg, &state).await?;
-                let pid: u32 = pid.parse()?;
-                let key = args.next().unwrap();
-                let key = eval_arg(&key, &state).await?;
-
-                let sock_path = sock_path_from_pid(pid);
-
-                let channel = create_channel(sock_path).await?;
-
-                let mut client = EnvClient::new(channel);
-
-                let request = Request::new(());
-
-                let resp = client.get_env(request).await?.into_inner();
-
-                let vars = resp
-                    .vars
-                    .into_iter()
-                    .map(|var| (var.key, var.value))
-                    .collect::<HashMap<_, _>>();
-
-                std::env::set_var(
-                    key.clone(),
-                    vars.get(&key).map(|v| v.clone()).unwrap_or_default(),
-                );
-
-                Some(CommandResult { output: None })
-            }
-            "pshl" => {
-                if args.len() != 0 {
-                    eprintln!("pshl doesn't take any args");
-                    return Ok(Some(CommandResult { output: None }));
-                }
-
-                let psh_path = PathBuf::from("/tmp/psh");
-
-                for dir in std::fs::read_dir(psh_path)? {
-                    if let Ok(dir) = dir {
-                        if let Ok(pid) = dir.file_name().to_string_lossy().parse::<u32>() {
-                            if pid == std::process::id() {
-                                continue;
-                            }
-                            let sock_path = sock_path_from_pid(pid);
-                            if let Ok(channel) = create_channel(sock_path).await {
-                                let mut client = StatusClient::new(channel);
-
-                                let request = Request::new(());
-
-                                if let Ok(resp) = client.get_status(request).await {
-                                    let resp = resp.into_inner();
-                                    println!(
-                                        "{}: {} ({})",
-                                        dir.file_name().to_string_lossy(),
-                                        resp.current_command,
-                                        resp.working_dir
-                                    );
-                                }
-                            }
-                        }
-                    }
-                }
-
-                Some(CommandResult { output: None })
-            }
-            "diffenv" => {
-                if args.len() != 1 {
-                    eprintln!("diffenv requires a process id");
-                    return Ok(Some(CommandResult { output: None }));
-                }
-
-                let arg = args.iter().next().unwrap();
-                let pid = eval_arg(&arg, &state).await?;
-                let pid: u32 = pid.parse()?;
-
-                let sock_path = sock_path_from_pid(pid);
-
-                let channel = create_channel(sock_path).await?;
-
-                let mut client = EnvClient::new(channel);
-
-                let request = Request::new(());
-
-                let resp = client.get_env(request).await?.into_inner();
-
-                let local_vars = std::env::vars().into_iter().collect::<HashMap<_, _>>();
-
-                for var in resp.vars {
-                    let local = local_vars.get(&var.key);
-
-                    if let Some(local) = local {
-                        if *local != var.value {
-                            println!(" {}:", var.key);
-                            println!("\tLocal:  {}", local);
-                            println!("\tTheirs: {}", var.value);
-                        }
-                    } else {
-                        println!("+{}: {}", var.key, var.value);
-                    }
-                }
-
-                Some(CommandResult { output: None })
-            }
-            "cd" => {
-                let new_directory = args.first();
-                let new_directory = if let Some(arg) = new_directory {
-                    Some(eval_arg(arg, state).await?)
-                } else {
-                    env::var("HOME").ok()
-                }
-                .unwrap_or_else(|| String::from("/"));
-                let new_directory = sub_var(&new_directory);
-                let newpath = Path::new(&new_directory);
-                if let Err(e) = env::set_current_dir(newpath) {
-                    eprintln!("{}", e);
-                }
-
-                Some(CommandResult { output: None })
-            }
-            "set" => {
-                let mut args = args.iter();
-                let key = args
-                    .next()
-                    .ok_or_else(|| anyhow::anyhow!("Set requires a key and value"))?;
-                if let Arg::String { arg_string: key } = key {
-                    let value = args
-                        .next()
-                        .ok_or_else(|| anyhow::anyhow!("Set requires a key and value"))?;
-                    let value = eval_arg(value, state).await?;
-                    env::set_var(key, value.trim());
-                } else {
-                    bail!("Key must be a string");
-                }
-                Some(CommandResult { output: None })
-            }
-            "alias" => {
-                if args.len() < 2 {
-                    let aliases = state.lock().unwrap().aliases.clone();
-                    for (_, alias) in aliases.iter() {
-                        println!("{}", alias.display());
-                    }
-                    return Ok(Some(CommandResult { output: None }));
-                }
-                let mut args = args.iter();
-                let alias = eval_arg(args.next().unwrap(), state).await?;
-                let command = eval_arg(args.next().unwrap(), state).await?;
-                let args = args.cloned().collect();
-
-                let aliasdef = Alias {
-                    alias: alias.to_owned(),
-                    command,
-                    args,
-                };
-
-                state.lock().unwrap().aliases.insert(alias, aliasdef);
-
-                Some(CommandResult { output: None })
-            }
-            "exit" => {
-                exit(0);
-            }
-            _ => None,
-        },
-        _ => None,
-    })
-}
+use crate::{
+    arith::Expr,
+    builtins::{self, BuiltinIo},
+    expand::{eval_param, eval_pattern, expand_args, expand_tilde, lookup_var, ParamOp},
+    job::{wait_for_job, Job, Process, INTERRUPTED_STATUS, STOPPED_STATUS},
+    redirect::{pipe, Io, Redirect, Stdios},
+    state::{Function, State},
+    subshell::{fork_subshell, place_stdio, substitute_process},
+};
 
 #[derive(Debug, Clone)]
 pub enum Arg {
-    String { arg_string: String },
-    Env { var_name: String },
-    Subcommand { command: Command },
+    String {
+        arg_string: String,
+    },
+    /// An unquoted word that needs tilde, brace or pathname expansion.
+    Word {
+        word: String,
+    },
+    Env {
+        var_name: String,
+    },
+    /// `${name}` and its variations.
+    Param {
+        name: String,
+        op: ParamOp,
+    },
+    Subcommand {
+        command: Command,
+    },
+    /// `<(list)`, or `>(list)` if `writable`: the path of a pipe from or to
+    /// the commands.
+    ProcessSubstitution {
+        command: Command,
+        writable: bool,
+    },
+    /// `$(( expr ))`
+    Arith {
+        expr: Expr,
+    },
+    Compound {
+        parts: Vec<Arg>,
+    },
 }
 
 impl Display for Arg {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Arg::String { arg_string } => arg_string.fmt(f),
-            Arg::Env { var_name } => var_name.fmt(f),
-            Arg::Subcommand { command } => f.write_fmt(format_args!("{:?}", command)),
+            Arg::Word { word } => word.fmt(f),
+            Arg::Env { var_name } => write!(f, "${}", var_name),
+            Arg::Param { name, op } => op.display(name, f),
+            Arg::Subcommand { command } => write!(f, "$({})", command),
+            Arg::ProcessSubstitution { command, writable } => {
+                write!(f, "{}({})", if *writable { ">" } else { "<" }, command)
+            }
+            Arg::Arith { expr } => write!(f, "$(({}))", expr),
+            Arg::Compound { parts } => parts.iter().try_for_each(|part| part.fmt(f)),
         }
     }
 }
 
+/// `NAME=value`, either before a command or on its own.
+#[derive(Debug, Clone)]
+pub struct Assignment {
+    pub name: String,
+    pub value: Arg,
+}
+
+impl Display for Assignment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.name, self.value)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Command {
     Simple {
+        /// Variables set for this command only or, with no command, for the
+        /// shell.
+        assignments: Vec<Assignment>,
         command: String,
         args: Vec<Arg>,
+        redirects: Vec<Redirect>,
     },
     Pipeline {
         steps: Vec<Command>,
-        redirect: Option<String>,
     },
     And {
         left: Box<Command>,
@@ -230,145 +109,826 @@ pub enum Command {
         left: Box<Command>,
         right: Box<Command>,
     },
+    Sequence {
+        left: Box<Command>,
+        right: Box<Command>,
+    },
+    Background {
+        command: Box<Command>,
+    },
+    /// `if list; then list; [elif list; then list;]... [else list;] fi`
+    If {
+        /// Each condition with the commands it guards, in order.
+        branches: Vec<(Command, Command)>,
+        otherwise: Option<Box<Command>>,
+    },
+    /// `while list; do list; done`, or with `until`, looping for as long as
+    /// the condition fails.
+    While {
+        condition: Box<Command>,
+        body: Box<Command>,
+        until: bool,
+    },
+    /// `for name in words...; do list; done`
+    For {
+        name: String,
+        words: Vec<Arg>,
+        body: Box<Command>,
+    },
+    /// `name() { list; }`, which defines a function.
+    Function {
+        function: Box<Function>,
+    },
+    /// `case word in pattern | pattern) list;; ... esac`
+    Case {
+        word: Box<Arg>,
+        items: Vec<CaseItem>,
+    },
+    /// `( list )`, run in a forked copy of the shell so that nothing it
+    /// changes outlives it.
+    Subshell {
+        body: Box<Command>,
+        redirects: Vec<Redirect>,
+    },
+    /// `{ list; }`, run in the shell itself, sharing its redirects.
+    Group {
+        body: Box<Command>,
+        redirects: Vec<Redirect>,
+    },
+}
+
+/// One `pattern | pattern) list;;` arm of a `case`.
+#[derive(Debug, Clone)]
+pub struct CaseItem {
+    pub patterns: Vec<Arg>,
+    pub body: Option<Command>,
+}
+
+impl Display for CaseItem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let patterns = self.patterns.iter().map(|p| p.to_string());
+        write!(f, "{})", patterns.collect::<Vec<_>>().join(" | "))?;
+        if let Some(body) = &self.body {
+            write!(f, " {}", body)?;
+        }
+        write!(f, ";;")
+    }
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Command::Simple {
+                assignments,
+                command,
+                args,
+                redirects,
+            } => {
+                let words = assignments.iter().map(|a| a.to_string());
+                let words = words.chain((!command.is_empty()).then(|| command.clone()));
+                words.collect::<Vec<_>>().join(" ").fmt(f)?;
+                for arg in args {
+                    write!(f, " {}", arg)?;
+                }
+                for redirect in redirects {
+                    write!(f, " {}", redirect)?;
+                }
+                Ok(())
+            }
+            Command::Pipeline { steps } => {
+                let steps = steps.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+                steps.join(" | ").fmt(f)
+            }
+            Command::And { left, right } => write!(f, "{} && {}", left, right),
+            Command::Or { left, right } => write!(f, "{} || {}", left, right),
+            Command::Sequence { left, right } => write!(f, "{}; {}", left, right),
+            Command::Background { command } => write!(f, "{} &", command),
+            Command::If {
+                branches,
+                otherwise,
+            } => {
+                for (idx, (condition, body)) in branches.iter().enumerate() {
+                    let keyword = if idx == 0 { "if" } else { "elif" };
+                    write!(f, "{} {}; then {}; ", keyword, condition, body)?;
+                }
+                if let Some(otherwise) = otherwise {
+                    write!(f, "else {}; ", otherwise)?;
+                }
+                write!(f, "fi")
+            }
+            Command::While {
+                condition,
+                body,
+                until,
+            } => {
+                let keyword = if *until { "until" } else { "while" };
+                write!(f, "{} {}; do {}; done", keyword, condition, body)
+            }
+            Command::For { name, words, body } => {
+                write!(f, "for {} in", name)?;
+                for word in words {
+                    write!(f, " {}", word)?;
+                }
+                write!(f, "; do {}; done", body)
+            }
+            Command::Function { function } => function.source.fmt(f),
+            Command::Case { word, items } => {
+                write!(f, "case {} in", word)?;
+                for item in items {
+                    write!(f, " {}", item)?;
+                }
+                write!(f, " esac")
+            }
+            Command::Subshell { body, redirects } => {
+                write!(f, "({})", body)?;
+                for redirect in redirects {
+                    write!(f, " {}", redirect)?;
+                }
+                Ok(())
+            }
+            Command::Group { body, redirects } => {
+                write!(f, "{{ {}; }}", body)?;
+                for redirect in redirects {
+                    write!(f, " {}", redirect)?;
+                }
+                Ok(())
+            }
+        }
+    }
 }
 
 pub struct CommandResult {
-    pub output: Option<Child>,
+    pub status: i32,
 }
 
 impl CommandResult {
-    pub fn stdout(&mut self) -> Option<Stdio> {
-        let mut output = self.output.take();
-        let stdio = if let Some(ref mut output) = output {
-            output.stdout.take().map(Stdio::from)
-        } else {
-            None
-        };
-        self.output = output;
-        stdio
+    pub fn success(&self) -> bool {
+        self.status == 0
+    }
+}
+
+/// Unwinds a function call when `return` runs, carrying the status the
+/// function ends with.
+#[derive(Debug)]
+pub struct Return {
+    pub status: i32,
+}
+
+impl Display for Return {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "return {}", self.status)
     }
 }
 
-fn sub_var(arg: &str) -> String {
-    if arg.starts_with('$') {
-        let key = arg.chars().skip(1).collect::<String>();
-        env::var(key).ok().unwrap_or_default()
-    } else {
-        arg.to_owned()
+impl std::error::Error for Return {}
+
+/// Unwinds out of loops when `break` or `continue` runs, through `levels`
+/// of them. `continue` goes on with the next pass of the last one.
+#[derive(Debug, Clone, Copy)]
+pub struct LoopJump {
+    pub levels: usize,
+    pub continuing: bool,
+}
+
+impl Display for LoopJump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = if self.continuing { "continue" } else { "break" };
+        write!(f, "{} {}", name, self.levels)
     }
 }
 
-async fn eval_arg(arg: &Arg, state: &Arc<Mutex<State>>) -> Result<String> {
+impl std::error::Error for LoopJump {}
+
+/// Where a loop goes after running its condition or body once.
+enum LoopFlow {
+    Next,
+    Break,
+    Continue,
+}
+
+/// How deeply functions and sourced scripts can call each other before psh
+/// gives up, rather than overflowing its stack.
+pub const MAX_FUNCTION_DEPTH: usize = 100;
+
+/// A single process started by [`Command::spawn`].
+enum Spawned {
+    /// Nothing was left running: either a builtin ran to completion or the
+    /// command couldn't be started at all.
+    Finished(CommandResult),
+    Process(Pid),
+}
+
+/// A job started by [`Command::start`].
+enum Started {
+    /// Nothing was left running, e.g. the command was a builtin. Holds the
+    /// exit code of each stage.
+    Finished(Vec<i32>),
+    Job(Job),
+}
+
+#[async_recursion]
+pub async fn eval_arg(arg: &Arg, state: &Arc<Mutex<State>>) -> Result<String> {
     Ok(match arg {
         Arg::String { arg_string } => arg_string.clone(),
-        Arg::Env { var_name } => env::var(var_name)?,
+        Arg::Word { word } => expand_tilde(word, state),
+        Arg::Env { var_name } => lookup_var(var_name, state).unwrap_or_default(),
+        Arg::Param { name, op } => eval_param(name, op, state).await?,
         Arg::Subcommand { command } => {
-            let output = command
-                .run(Stdio::null(), Stdio::piped(), state)
-                .await?
-                .output
-                .ok_or_else(|| anyhow::anyhow!("Error running subcomand"))?
-                .wait_with_output()?;
-
-            if !output.status.success() {
+            let (mut read, write) = pipe()?;
+            let reader = thread::spawn(move || {
+                let mut stdout = vec![];
+                read.read_to_end(&mut stdout).map(|_| stdout)
+            });
+
+            // Like `( )`, nothing the command does to the shell outlasts it.
+            let stdios = Stdios::new(Io::Null, Io::File(write));
+            let pid = fork_subshell(command, stdios, None, state)?;
+            let job = Job::new(command.to_string(), pid, vec![Process::running(pid)]);
+            let statuses = wait_for_job(job, false, state).await?;
+            let stdout = reader
+                .join()
+                .map_err(|_| anyhow::anyhow!("Error reading subcommand output"))??;
+
+            if statuses.iter().any(|status| *status != 0) {
                 bail!("Error running subcommand");
             }
 
-            let out_str = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+            let out_str = String::from_utf8_lossy(&stdout).trim().to_owned();
 
             out_str
         }
+        Arg::ProcessSubstitution { command, writable } => {
+            substitute_process(command, *writable, state)?
+        }
+        Arg::Arith { expr } => expr.eval(state)?.to_string(),
+        Arg::Compound { parts } => {
+            let mut joined = String::new();
+            for part in parts {
+                joined.push_str(&eval_arg(part, state).await?);
+            }
+            joined
+        }
     })
 }
 
+/// Commands only pass stdin and stdout along, so for something run in psh's
+/// own process, a redirected stderr replaces the shell's own until it's done.
+/// Returns the original, for [`restore_stderr`].
+pub fn replace_stderr(stderr: Io) -> Result<Option<RawFd>> {
+    Ok(match stderr {
+        Io::Inherit => None,
+        stderr => {
+            let saved = fcntl(2, FcntlArg::F_DUPFD_CLOEXEC(3))?;
+            place_stdio(stderr, 2)?;
+            Some(saved)
+        }
+    })
+}
+
+pub fn restore_stderr(saved: Option<RawFd>) -> Result<()> {
+    if let Some(saved) = saved {
+        dup2(saved, 2)?;
+        close(saved)?;
+    }
+
+    Ok(())
+}
+
+/// Runs a shell function in psh's own process, with `args` as its positional
+/// parameters.
+async fn call_function(
+    function: &Function,
+    args: Vec<String>,
+    stdios: Stdios,
+    state: &Arc<Mutex<State>>,
+) -> Result<CommandResult> {
+    let saved_positional = {
+        let mut state = state.lock().unwrap();
+        if state.function_depth >= MAX_FUNCTION_DEPTH {
+            eprintln!(
+                "psh: {}: maximum function nesting level exceeded",
+                function.name
+            );
+            return Ok(CommandResult { status: 1 });
+        }
+        state.function_depth += 1;
+        std::mem::replace(&mut state.positional, args)
+    };
+
+    // The shell goes back to how it was even if this fails.
+    let result = match replace_stderr(stdios.stderr) {
+        Ok(saved_stderr) => {
+            let result = function.body.run(stdios.stdin, stdios.stdout, state).await;
+            restore_stderr(saved_stderr).and(result)
+        }
+        Err(e) => Err(e),
+    };
+    {
+        let mut state = state.lock().unwrap();
+        state.positional = saved_positional;
+        state.function_depth -= 1;
+    }
+
+    match result {
+        Err(e) => match e.downcast::<Return>() {
+            Ok(Return { status }) => Ok(CommandResult { status }),
+            Err(e) => Err(e),
+        },
+        result => result,
+    }
+}
+
 impl Command {
+    /// Whether this is a call to a shell function.
+    fn calls_function(&self, state: &Arc<Mutex<State>>) -> bool {
+        match self {
+            Command::Simple { command, .. } => {
+                state.lock().unwrap().functions.contains_key(command)
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether this is a simple command that runs in the shell itself rather
+    /// than as a program of its own: a function, a builtin or just variable
+    /// assignments.
+    fn runs_in_shell(&self, state: &Arc<Mutex<State>>) -> bool {
+        match self {
+            Command::Simple { command, .. } => {
+                let state = state.lock().unwrap();
+                command.is_empty()
+                    || state.functions.contains_key(command)
+                    || state.builtins.get(command).is_some()
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether this runs a script in the shell itself, with `source` or `.`.
+    fn sources_script(&self, state: &Arc<Mutex<State>>) -> bool {
+        match self {
+            Command::Simple { command, .. } => {
+                matches!(command.as_str(), "source" | ".")
+                    && !state.lock().unwrap().functions.contains_key(command)
+            }
+            _ => false,
+        }
+    }
+
+    /// Starts the command as a single process in process group `pgid`, or a
+    /// new group if that's `None`, if the shell does job control. Anything
+    /// other than a simple command runs in a subshell.
     #[async_recursion]
+    async fn spawn(
+        &self,
+        stdin: Io,
+        stdout: Io,
+        pgid: Option<Pid>,
+        state: &Arc<Mutex<State>>,
+    ) -> Result<Spawned> {
+        let Command::Simple {
+            assignments,
+            command,
+            args,
+            redirects,
+        } = self
+        else {
+            let mut stdios = Stdios::new(stdin, stdout);
+            // A subshell is already one, so its child runs just the body.
+            let command = match self {
+                Command::Subshell { body, redirects } => {
+                    if let Err(e) = stdios.apply(redirects, state).await {
+                        eprintln!("psh: {}", e);
+                        return Ok(Spawned::Finished(CommandResult { status: 1 }));
+                    }
+                    body
+                }
+                command => command,
+            };
+            return Ok(Spawned::Process(fork_subshell(
+                command, stdios, pgid, state,
+            )?));
+        };
+
+        let mut vars = vec![];
+        for Assignment { name, value } in assignments {
+            match eval_arg(value, state).await {
+                // On their own, each assignment can see the ones before it.
+                Ok(value) if command.is_empty() => {
+                    if let Err(e) = state.lock().unwrap().vars.set(name, value) {
+                        eprintln!("psh: {}", e);
+                        return Ok(Spawned::Finished(CommandResult { status: 1 }));
+                    }
+                }
+                Ok(value) => vars.push((name.clone(), value)),
+                Err(e) => {
+                    eprintln!("psh: {}", e);
+                    return Ok(Spawned::Finished(CommandResult { status: 1 }));
+                }
+            }
+        }
+        if command.is_empty() {
+            return Ok(Spawned::Finished(CommandResult { status: 0 }));
+        }
+        let mut stdios = Stdios::new(stdin, stdout);
+        if let Err(e) = stdios.apply(redirects, state).await {
+            eprintln!("psh: {}", e);
+            return Ok(Spawned::Finished(CommandResult { status: 1 }));
+        }
+        let function = state.lock().unwrap().functions.get(command).cloned();
+        let builtin = state.lock().unwrap().builtins.get(command);
+        let (command, args) = match state.lock().unwrap().aliases.get(command) {
+            Some(alias) if function.is_none() && builtin.is_none() => {
+                let mut merged_args = alias.args.clone();
+                merged_args.append(&mut args.clone());
+                (alias.command.clone(), merged_args)
+            }
+            _ => (command.clone(), args.clone()),
+        };
+        let arg_vec = match expand_args(&args, state).await {
+            Ok(arg_vec) => arg_vec,
+            Err(e) => {
+                eprintln!("psh: {}", e);
+                return Ok(Spawned::Finished(CommandResult { status: 1 }));
+            }
+        };
+        // Functions and builtins run in psh's own process, so the variables
+        // go in a scope of their own that's dropped once they're done. A
+        // builtin without any leaves the scope alone, so `local` can reach
+        // the function it's in.
+        let scoped = function.is_some() || (builtin.is_some() && !vars.is_empty());
+        if scoped {
+            if let Err(e) = state.lock().unwrap().vars.push_temporary(&vars) {
+                eprintln!("psh: {}", e);
+                return Ok(Spawned::Finished(CommandResult { status: 1 }));
+            }
+        }
+        if let Some(function) = function {
+            let result = call_function(&function, arg_vec, stdios, state).await;
+            state.lock().unwrap().vars.pop_scope();
+
+            return Ok(Spawned::Finished(result?));
+        }
+        if let Some(builtin) = builtin {
+            let mut io = BuiltinIo::new(stdios)?;
+            let result = builtins::run(builtin.as_ref(), arg_vec, &mut io, state).await;
+            io.finish();
+            let (returning, jumping) = {
+                let mut state = state.lock().unwrap();
+                if scoped {
+                    state.vars.pop_scope();
+                }
+                (state.returning.take(), state.jumping.take())
+            };
+            if let Some(status) = returning {
+                return Err(Return { status }.into());
+            }
+            if let Some(jump) = jumping {
+                return Err(jump.into());
+            }
+
+            return Ok(Spawned::Finished(result));
+        }
+        let (exported, interactive) = {
+            let mut state = state.lock().unwrap();
+            state.current_command = Some(command.clone());
+            (state.vars.exported(), state.interactive)
+        };
+        let mut child = OsCommand::new(&command);
+        child
+            .args(arg_vec)
+            .env_clear()
+            .envs(exported)
+            .envs(vars)
+            .stdin(stdios.stdin)
+            .stdout(stdios.stdout)
+            .stderr(stdios.stderr);
+        if interactive {
+            child.process_group(pgid.map(Pid::as_raw).unwrap_or(0));
+        }
+        let child = child.spawn();
+
+        Ok(match child {
+            Ok(child) => Spawned::Process(Pid::from_raw(child.id() as i32)),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                eprintln!("psh: {}: command not found", command);
+                Spawned::Finished(CommandResult { status: 127 })
+            }
+            Err(e) => {
+                eprintln!("psh: {}: {}", command, e);
+                Spawned::Finished(CommandResult { status: 126 })
+            }
+        })
+    }
+
+    /// Starts the command as a job in its own process group without waiting
+    /// for it.
+    async fn start(&self, stdin: Io, stdout: Io, state: &Arc<Mutex<State>>) -> Result<Started> {
+        let Command::Pipeline { steps } = self else {
+            return Ok(match self.spawn(stdin, stdout, None, state).await? {
+                Spawned::Finished(result) => Started::Finished(vec![result.status]),
+                Spawned::Process(pid) => {
+                    Started::Job(Job::new(self.to_string(), pid, vec![Process::running(pid)]))
+                }
+            });
+        };
+
+        let mut pgid = None;
+        let mut processes = vec![];
+        let mut stdin = stdin;
+        let mut stdout = Some(stdout);
+        let count = steps.len();
+        for (idx, command) in steps.iter().enumerate() {
+            let end = idx + 1 == count;
+            let (next_stdin, step_stdout) = if end {
+                (Io::Null, stdout.take().unwrap())
+            } else {
+                let (read, write) = pipe()?;
+                (Io::File(read), Io::File(write))
+            };
+
+            // A function runs in the shell itself, which would block if it
+            // wrote more than a pipe holds before the next stage started, so
+            // in a pipeline it gets a subshell. So does a sourced script
+            // with a stage after it to read what it writes.
+            let spawned =
+                if command.calls_function(state) || (!end && command.sources_script(state)) {
+                    let stdios = Stdios::new(stdin, step_stdout);
+                    Spawned::Process(fork_subshell(command, stdios, pgid, state)?)
+                } else {
+                    command.spawn(stdin, step_stdout, pgid, state).await?
+                };
+            match spawned {
+                Spawned::Finished(result) => processes.push(Process::finished(result.status)),
+                Spawned::Process(pid) => {
+                    pgid.get_or_insert(pid);
+                    processes.push(Process::running(pid));
+                }
+            }
+
+            stdin = next_stdin;
+        }
+
+        Ok(match pgid {
+            Some(pgid) => Started::Job(Job::new(self.to_string(), pgid, processes)),
+            None => Started::Finished(processes.iter().map(Process::exit_code).collect()),
+        })
+    }
+
+    /// Runs a `while`, `until` or `for` loop, returning its exit code.
+    async fn run_loop(&self, stdin: Io, stdout: Io, state: &Arc<Mutex<State>>) -> Result<i32> {
+        let mut status = 0;
+        match self {
+            Command::While {
+                condition,
+                body,
+                until,
+            } => loop {
+                let (result, flow) = run_loop_part(condition, &stdin, &stdout, state).await?;
+                match flow {
+                    LoopFlow::Break => break,
+                    LoopFlow::Continue => continue,
+                    LoopFlow::Next if (result == 0) == *until => break,
+                    LoopFlow::Next => {}
+                }
+                let (result, flow) = run_loop_part(body, &stdin, &stdout, state).await?;
+                status = result;
+                if let LoopFlow::Break = flow {
+                    break;
+                }
+            },
+            Command::For { name, words, body } => {
+                let words = match expand_args(words, state).await {
+                    Ok(words) => words,
+                    Err(e) => {
+                        eprintln!("psh: {}", e);
+                        return Ok(1);
+                    }
+                };
+                for word in words {
+                    if let Err(e) = state.lock().unwrap().vars.set(name, word) {
+                        eprintln!("psh: {}", e);
+                        return Ok(1);
+                    }
+                    let (result, flow) = run_loop_part(body, &stdin, &stdout, state).await?;
+                    status = result;
+                    if let LoopFlow::Break = flow {
+                        break;
+                    }
+                }
+            }
+            _ => unreachable!("not a loop"),
+        }
+
+        Ok(status)
+    }
+
+    /// Runs the command to completion, or until it's stopped.
     pub async fn run(
         &self,
-        stdin: Stdio,
-        stdout: Stdio,
+        stdin: Io,
+        stdout: Io,
+        state: &Arc<Mutex<State>>,
+    ) -> Result<CommandResult> {
+        // Process substitutions last as long as the command they're part of.
+        let substitutions = state.lock().unwrap().substitutions.len();
+        let result = self.execute(stdin, stdout, state).await;
+        state.lock().unwrap().end_substitutions(substitutions);
+
+        result
+    }
+
+    #[async_recursion]
+    async fn execute(
+        &self,
+        stdin: Io,
+        stdout: Io,
         state: &Arc<Mutex<State>>,
     ) -> Result<CommandResult> {
         Ok(match self {
-            Command::Simple { command, args } => {
-                if command.is_empty() {
-                    return Ok(CommandResult { output: None });
+            Command::Simple { .. } | Command::Pipeline { .. } | Command::Subshell { .. } => {
+                state.lock().unwrap().apply_pending_vars();
+                let statuses = match self.start(stdin, stdout, state).await? {
+                    Started::Finished(statuses) => statuses,
+                    Started::Job(job) => wait_for_job(job, false, state).await?,
+                };
+
+                CommandResult {
+                    status: state.lock().unwrap().record_statuses(statuses),
                 }
-                if let Some(result) = run_builtin(self, state).await? {
-                    return Ok(result);
+            }
+            Command::And { left, right } => {
+                let lresult = left
+                    .run(stdin.try_clone()?, stdout.try_clone()?, state)
+                    .await?;
+                if !lresult.success() {
+                    return Ok(lresult);
                 }
-                let (command, args) =
-                    if let Some(alias) = state.lock().unwrap().aliases.get(command) {
-                        let mut merged_args = alias.args.clone();
-                        merged_args.append(&mut args.clone());
-                        (alias.command.clone(), merged_args)
-                    } else {
-                        (command.clone(), args.clone())
-                    };
-                let mut arg_vec = vec![];
-                for arg in args {
-                    arg_vec.push(eval_arg(&arg, state).await?);
+
+                right.run(stdin, stdout, state).await?
+            }
+            Command::Or { left, right } => {
+                let lresult = left
+                    .run(stdin.try_clone()?, stdout.try_clone()?, state)
+                    .await?;
+                if lresult.success() {
+                    return Ok(lresult);
+                }
+
+                right.run(stdin, stdout, state).await?
+            }
+            Command::Sequence { left, right } => {
+                left.run(stdin.try_clone()?, stdout.try_clone()?, state)
+                    .await?;
+
+                right.run(stdin, stdout, state).await?
+            }
+            Command::Background { command } => {
+                // Anything run in the shell itself would otherwise run to the
+                // end before anything after it got a look in, and change the
+                // shell as it went, e.g. `cd`.
+                let started = if command.runs_in_shell(state) {
+                    let stdios = Stdios::new(Io::Null, stdout);
+                    let pid = fork_subshell(command, stdios, None, state)?;
+                    Started::Job(Job::new(
+                        command.to_string(),
+                        pid,
+                        vec![Process::running(pid)],
+                    ))
+                } else {
+                    command.start(Io::Null, stdout, state).await?
+                };
+                if let Started::Job(job) = started {
+                    let pgid = job.pgid;
+                    let mut state = state.lock().unwrap();
+                    let id = state.add_job(job);
+                    if state.interactive {
+                        eprintln!("[{}] {}", id, pgid);
+                    }
                 }
-                state.lock().unwrap().current_command = Some(command.clone());
-                let output = OsCommand::new(command)
-                    .args(arg_vec)
-                    .stdin(stdin)
-                    .stdout(stdout)
-                    .spawn()?;
-                state.lock().unwrap().running_pid = Some(output.id());
 
                 CommandResult {
-                    output: Some(output),
+                    status: state.lock().unwrap().record_statuses(vec![0]),
                 }
             }
-            Command::Pipeline { steps, redirect } => {
-                if steps.is_empty() {
-                    return Ok(CommandResult { output: None });
-                }
-                let count = steps.len();
-                let mut stdin = stdin;
-                for (idx, command) in steps.iter().enumerate() {
-                    let end = idx + 1 == count;
-                    if end {
-                        if let Some(redirect) = redirect {
-                            let file = File::create(redirect)?;
-                            let fileout = Stdio::from(file);
-                            return command.run(stdin, fileout, state).await;
-                        }
-                        return command.run(stdin, stdout, state).await;
+            Command::If {
+                branches,
+                otherwise,
+            } => {
+                for (condition, body) in branches {
+                    let result = condition
+                        .run(stdin.try_clone()?, stdout.try_clone()?, state)
+                        .await?;
+                    if result.success() {
+                        return body.run(stdin, stdout, state).await;
                     }
+                }
 
-                    let mut last = command.run(stdin, Stdio::piped(), state).await?;
+                match otherwise {
+                    Some(otherwise) => otherwise.run(stdin, stdout, state).await?,
+                    None => CommandResult {
+                        status: state.lock().unwrap().record_statuses(vec![0]),
+                    },
+                }
+            }
+            Command::While { .. } | Command::For { .. } => {
+                state.lock().unwrap().loop_depth += 1;
+                let status = self.run_loop(stdin, stdout, state).await;
+                state.lock().unwrap().loop_depth -= 1;
 
-                    stdin = last.stdout().unwrap_or_else(Stdio::null);
+                CommandResult {
+                    status: state.lock().unwrap().record_statuses(vec![status?]),
                 }
+            }
+            Command::Function { function } => {
+                let mut state = state.lock().unwrap();
+                let function = *function.clone();
+                state.functions.insert(function.name.clone(), function);
 
-                unreachable!()
+                CommandResult {
+                    status: state.record_statuses(vec![0]),
+                }
             }
-            Command::And { left, right } => {
-                let lresult = left.run(stdin, Stdio::inherit(), state).await?;
-                let mut output = lresult.output.unwrap();
+            Command::Case { word, items } => {
+                let word = match eval_arg(word, state).await {
+                    Ok(word) => word,
+                    Err(e) => {
+                        eprintln!("psh: {}", e);
+                        return Ok(CommandResult {
+                            status: state.lock().unwrap().record_statuses(vec![1]),
+                        });
+                    }
+                };
+                for item in items {
+                    for pattern in item.patterns.iter() {
+                        if !eval_pattern(pattern, state).await?.matches(&word) {
+                            continue;
+                        }
+                        return match &item.body {
+                            Some(body) => body.run(stdin, stdout, state).await,
+                            None => Ok(CommandResult {
+                                status: state.lock().unwrap().record_statuses(vec![0]),
+                            }),
+                        };
+                    }
+                }
 
-                if !output.wait()?.success() {
+                CommandResult {
+                    status: state.lock().unwrap().record_statuses(vec![0]),
+                }
+            }
+            Command::Group { body, redirects } => {
+                let mut stdios = Stdios::new(stdin, stdout);
+                if let Err(e) = stdios.apply(redirects, state).await {
+                    eprintln!("psh: {}", e);
                     return Ok(CommandResult {
-                        output: Some(output),
+                        status: state.lock().unwrap().record_statuses(vec![1]),
                     });
                 }
+                let saved_stderr = replace_stderr(stdios.stderr)?;
+                let result = body.run(stdios.stdin, stdios.stdout, state).await;
 
-                right.run(Stdio::null(), Stdio::inherit(), state).await?
+                restore_stderr(saved_stderr).and(result)?
             }
-            Command::Or { left, right } => {
-                let lresult = left.run(stdin, Stdio::inherit(), state).await?;
-                let mut output = lresult.output.unwrap();
+        })
+    }
+}
 
-                if output.wait()?.success() {
-                    CommandResult {
-                        output: Some(output),
-                    }
+/// Runs a loop's condition or body once, catching a `break` or `continue`
+/// meant for this loop and passing on ones for loops further out.
+async fn run_loop_part(
+    command: &Command,
+    stdin: &Io,
+    stdout: &Io,
+    state: &Arc<Mutex<State>>,
+) -> Result<(i32, LoopFlow)> {
+    let result = command
+        .run(stdin.try_clone()?, stdout.try_clone()?, state)
+        .await;
+    match result {
+        Ok(CommandResult { status }) if ends_loop(status) => Ok((status, LoopFlow::Break)),
+        Ok(CommandResult { status }) => Ok((status, LoopFlow::Next)),
+        Err(e) => match e.downcast::<LoopJump>() {
+            Ok(LoopJump { levels, continuing }) if levels > 1 => Err(LoopJump {
+                levels: levels - 1,
+                continuing,
+            }
+            .into()),
+            Ok(LoopJump { continuing, .. }) => {
+                let flow = if continuing {
+                    LoopFlow::Continue
                 } else {
-                    right.run(Stdio::null(), Stdio::inherit(), state).await?
-                }
+                    LoopFlow::Break
+                };
+                Ok((0, flow))
             }
-        })
+            Err(e) => Err(e),
+        },
     }
 }
+
+/// Whether a loop should stop after a command with this exit code, because
+/// it was interrupted or stopped from the terminal.
+fn ends_loop(status: i32) -> bool {
+    status == INTERRUPTED_STATUS || status == STOPPED_STATUS
+}
@@ -0,0 +1,510 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Result};
+use async_recursion::async_recursion;
+use glob::{MatchOptions, Pattern};
+use nix::unistd::{getuid, User};
+
+use crate::{
+    command::{eval_arg, Arg},
+    state::{NoMatch, State},
+};
+
+/// What a `${name...}` expansion does with the variable.
+#[derive(Debug, Clone)]
+pub enum ParamOp {
+    /// `${name}`
+    Value,
+    /// `${#name}`
+    Length,
+    /// `${name:-word}` and `${name-word}`. Colon forms treat an empty value
+    /// the same as an unset one, here and below.
+    Default { word: Box<Arg>, colon: bool },
+    /// `${name:?word}` and `${name?word}`
+    Error { word: Box<Arg>, colon: bool },
+    /// `${name:+word}` and `${name+word}`
+    Alternative { word: Box<Arg>, colon: bool },
+    /// `${name%pattern}` and, if `longest`, `${name%%pattern}`
+    RemoveSuffix { pattern: Box<Arg>, longest: bool },
+    /// `${name#pattern}` and, if `longest`, `${name##pattern}`
+    RemovePrefix { pattern: Box<Arg>, longest: bool },
+}
+
+impl ParamOp {
+    pub fn display(&self, name: &str, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let colon = |colon: &bool| if *colon { ":" } else { "" };
+        match self {
+            ParamOp::Value => write!(f, "${{{}}}", name),
+            ParamOp::Length => write!(f, "${{#{}}}", name),
+            ParamOp::Default { word, colon: c } => write!(f, "${{{}{}-{}}}", name, colon(c), word),
+            ParamOp::Error { word, colon: c } => write!(f, "${{{}{}?{}}}", name, colon(c), word),
+            ParamOp::Alternative { word, colon: c } => {
+                write!(f, "${{{}{}+{}}}", name, colon(c), word)
+            }
+            ParamOp::RemoveSuffix { pattern, longest } => {
+                let op = if *longest { "%%" } else { "%" };
+                write!(f, "${{{}{}{}}}", name, op, pattern)
+            }
+            ParamOp::RemovePrefix { pattern, longest } => {
+                let op = if *longest { "##" } else { "#" };
+                write!(f, "${{{}{}{}}}", name, op, pattern)
+            }
+        }
+    }
+}
+
+/// The value of a variable, including the shell's special ones. `None` if
+/// it isn't set.
+pub fn lookup_var(name: &str, state: &Arc<Mutex<State>>) -> Option<String> {
+    match name {
+        "?" => Some(state.lock().unwrap().last_status.to_string()),
+        "0" => Some(state.lock().unwrap().shell_name.clone()),
+        "#" => Some(state.lock().unwrap().positional.len().to_string()),
+        "@" | "*" => Some(state.lock().unwrap().positional.join(" ")),
+        _ if name.bytes().all(|b| b.is_ascii_digit()) => {
+            let index = name.parse::<usize>().ok()?.checked_sub(1)?;
+            state.lock().unwrap().positional.get(index).cloned()
+        }
+        "PIPESTATUS" => {
+            let state = state.lock().unwrap();
+            let statuses = state.pipestatus.iter().map(|s| s.to_string());
+            Some(statuses.collect::<Vec<_>>().join(" "))
+        }
+        _ => state.lock().unwrap().vars.get(name).map(str::to_owned),
+    }
+}
+
+#[async_recursion]
+pub async fn eval_param(name: &str, op: &ParamOp, state: &Arc<Mutex<State>>) -> Result<String> {
+    let value = lookup_var(name, state);
+    let is_set = |colon: &bool| match &value {
+        Some(value) => !(*colon && value.is_empty()),
+        None => false,
+    };
+
+    Ok(match op {
+        ParamOp::Value => value.unwrap_or_default(),
+        ParamOp::Length => value.unwrap_or_default().chars().count().to_string(),
+        ParamOp::Default { word, colon } => {
+            if is_set(colon) {
+                value.unwrap_or_default()
+            } else {
+                eval_arg(word, state).await?
+            }
+        }
+        ParamOp::Error { word, colon } => {
+            if !is_set(colon) {
+                let message = eval_arg(word, state).await?;
+                if message.is_empty() {
+                    bail!("{}: parameter null or not set", name);
+                }
+                bail!("{}: {}", name, message);
+            }
+            value.unwrap_or_default()
+        }
+        ParamOp::Alternative { word, colon } => {
+            if is_set(colon) {
+                eval_arg(word, state).await?
+            } else {
+                String::new()
+            }
+        }
+        ParamOp::RemoveSuffix { pattern, longest } => {
+            let value = value.unwrap_or_default();
+            let pattern = literal_or_pattern(&eval_arg(pattern, state).await?);
+            let mut starts = value.char_indices().map(|(idx, _)| idx).collect::<Vec<_>>();
+            starts.push(value.len());
+            if !longest {
+                starts.reverse();
+            }
+            match starts
+                .into_iter()
+                .find(|idx| pattern.matches(&value[*idx..]))
+            {
+                Some(idx) => value[..idx].to_owned(),
+                None => value,
+            }
+        }
+        ParamOp::RemovePrefix { pattern, longest } => {
+            let value = value.unwrap_or_default();
+            let pattern = literal_or_pattern(&eval_arg(pattern, state).await?);
+            let mut ends = value.char_indices().map(|(idx, _)| idx).collect::<Vec<_>>();
+            ends.push(value.len());
+            if *longest {
+                ends.reverse();
+            }
+            match ends.into_iter().find(|idx| pattern.matches(&value[..*idx])) {
+                Some(idx) => value[idx..].to_owned(),
+                None => value,
+            }
+        }
+    })
+}
+
+/// `text` as a glob pattern, or matching itself literally if it isn't one.
+fn literal_or_pattern(text: &str) -> Pattern {
+    Pattern::new(text).unwrap_or_else(|_| Pattern::new(&Pattern::escape(text)).unwrap())
+}
+
+/// Evaluates a `case` pattern. Quoted parts of it only match themselves.
+#[async_recursion]
+pub async fn eval_pattern(arg: &Arg, state: &Arc<Mutex<State>>) -> Result<Pattern> {
+    let pattern = match arg {
+        Arg::Word { word } => word.clone(),
+        Arg::Compound { parts } => {
+            let mut pattern = String::new();
+            for part in parts {
+                match part {
+                    Arg::Word { word } => pattern.push_str(word),
+                    _ => pattern.push_str(&Pattern::escape(&eval_arg(part, state).await?)),
+                }
+            }
+            pattern
+        }
+        _ => Pattern::escape(&eval_arg(arg, state).await?),
+    };
+
+    Ok(literal_or_pattern(&pattern))
+}
+
+/// A piece of an argument, which only undergoes tilde, brace and pathname
+/// expansion if it was written unquoted.
+#[derive(Debug, Clone)]
+enum Segment {
+    Unquoted(String),
+    Quoted(String),
+}
+
+/// Whether `arg` is `$@` or `"$@"`, which become one word per positional
+/// parameter.
+fn is_all_positional(arg: &Arg) -> bool {
+    let is_at = |arg: &Arg| matches!(arg, Arg::Env { var_name } if var_name == "@");
+    match arg {
+        Arg::Compound { parts } => matches!(parts.as_slice(), [part] if is_at(part)),
+        _ => is_at(arg),
+    }
+}
+
+/// Evaluates a command's arguments into the words it's run with. Unlike
+/// [`eval_arg`], a single argument can become any number of words.
+pub async fn expand_args(args: &[Arg], state: &Arc<Mutex<State>>) -> Result<Vec<String>> {
+    let nomatch = state.lock().unwrap().options.nomatch;
+    let mut words = vec![];
+    for arg in args {
+        if is_all_positional(arg) {
+            words.extend(state.lock().unwrap().positional.iter().cloned());
+            continue;
+        }
+        let parts = match arg {
+            Arg::Word { .. } => std::slice::from_ref(arg),
+            Arg::Compound { parts } if parts.iter().any(|p| matches!(p, Arg::Word { .. })) => parts,
+            _ => {
+                words.push(eval_arg(arg, state).await?);
+                continue;
+            }
+        };
+
+        let mut segments = vec![];
+        for part in parts {
+            segments.push(match part {
+                Arg::Word { word } => Segment::Unquoted(word.clone()),
+                _ => Segment::Quoted(eval_arg(part, state).await?),
+            });
+        }
+        for segments in expand_segment_braces(segments) {
+            words.append(&mut expand_segments(segments, nomatch, state)?);
+        }
+    }
+
+    Ok(words)
+}
+
+/// Brace-expands each unquoted segment, returning every combination.
+fn expand_segment_braces(segments: Vec<Segment>) -> Vec<Vec<Segment>> {
+    let mut combinations = vec![vec![]];
+    for segment in segments {
+        let alternatives = match segment {
+            Segment::Unquoted(text) => expand_braces(&text)
+                .into_iter()
+                .map(Segment::Unquoted)
+                .collect(),
+            quoted => vec![quoted],
+        };
+        combinations = combinations
+            .into_iter()
+            .flat_map(|combination| {
+                alternatives.iter().map(move |alternative| {
+                    let mut combination = combination.clone();
+                    combination.push(alternative.clone());
+                    combination
+                })
+            })
+            .collect();
+    }
+
+    combinations
+}
+
+/// Tilde- and pathname-expands one brace-expanded word.
+fn expand_segments(
+    mut segments: Vec<Segment>,
+    nomatch: NoMatch,
+    state: &Arc<Mutex<State>>,
+) -> Result<Vec<String>> {
+    if let Some(Segment::Unquoted(first)) = segments.first_mut() {
+        *first = expand_tilde(first, state);
+    }
+
+    let mut literal = String::new();
+    let mut pattern = String::new();
+    let mut is_glob = false;
+    for segment in segments {
+        match segment {
+            Segment::Unquoted(text) => {
+                is_glob |= text.contains(['*', '?', '[']);
+                literal.push_str(&text);
+                pattern.push_str(&text);
+            }
+            Segment::Quoted(text) => {
+                literal.push_str(&text);
+                pattern.push_str(&Pattern::escape(&text));
+            }
+        }
+    }
+
+    if is_glob {
+        glob_words(&pattern, &literal, nomatch)
+    } else {
+        Ok(vec![literal])
+    }
+}
+
+/// Replaces a leading `~` or `~user` with that user's home directory. Words
+/// naming a user that doesn't exist are left alone.
+pub fn expand_tilde(word: &str, state: &Arc<Mutex<State>>) -> String {
+    let Some(rest) = word.strip_prefix('~') else {
+        return word.to_owned();
+    };
+    let (user, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+
+    let home = if user.is_empty() {
+        lookup_var("HOME", state).or_else(|| {
+            Some(
+                User::from_uid(getuid())
+                    .ok()??
+                    .dir
+                    .to_string_lossy()
+                    .into_owned(),
+            )
+        })
+    } else {
+        User::from_name(user)
+            .ok()
+            .flatten()
+            .map(|user| user.dir.to_string_lossy().into_owned())
+    };
+
+    match home {
+        Some(home) => format!("{}{}", home, path),
+        None => word.to_owned(),
+    }
+}
+
+/// Expands the first `{a,b}` or `{1..3}` group in `word`, then the rest of
+/// them in each result. Braces that are neither are left as they are.
+fn expand_braces(word: &str) -> Vec<String> {
+    for (open, _) in word.match_indices('{') {
+        let mut depth = 0;
+        let mut commas = vec![];
+        let mut close = None;
+        for (idx, c) in word[open..].char_indices() {
+            match c {
+                '{' => depth += 1,
+                '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        close = Some(open + idx);
+                        break;
+                    }
+                }
+                ',' if depth == 1 => commas.push(open + idx),
+                _ => {}
+            }
+        }
+        let Some(close) = close else {
+            continue;
+        };
+
+        let alternatives = if commas.is_empty() {
+            match brace_range(&word[open + 1..close]) {
+                Some(range) => range,
+                None => continue,
+            }
+        } else {
+            let mut bounds = vec![open];
+            bounds.extend(commas);
+            bounds.push(close);
+            bounds
+                .windows(2)
+                .map(|pair| word[pair[0] + 1..pair[1]].to_owned())
+                .collect()
+        };
+
+        let (prefix, suffix) = (&word[..open], &word[close + 1..]);
+        return alternatives
+            .into_iter()
+            .flat_map(|alternative| expand_braces(&format!("{}{}{}", prefix, alternative, suffix)))
+            .collect();
+    }
+
+    vec![word.to_owned()]
+}
+
+/// The items of a `1..5` or `a..e` range, which may count down.
+fn brace_range(inner: &str) -> Option<Vec<String>> {
+    let (from, to) = inner.split_once("..")?;
+    if let (Ok(from), Ok(to)) = (from.parse::<i64>(), to.parse::<i64>()) {
+        let items = if from <= to {
+            (from..=to).collect::<Vec<_>>()
+        } else {
+            (to..=from).rev().collect()
+        };
+        return Some(items.into_iter().map(|n| n.to_string()).collect());
+    }
+
+    let (mut from, mut to) = (from.chars(), to.chars());
+    let (Some(from), None, Some(to), None) = (from.next(), from.next(), to.next(), to.next())
+    else {
+        return None;
+    };
+    let items = if from <= to {
+        (from..=to).collect::<Vec<_>>()
+    } else {
+        (to..=from).rev().collect()
+    };
+
+    Some(items.into_iter().map(String::from).collect())
+}
+
+/// The paths matching `pattern`, in sorted order, or what `nomatch` says to
+/// use instead of `literal` if there aren't any. Hidden files only match a
+/// pattern that spells out the leading dot.
+fn glob_words(pattern: &str, literal: &str, nomatch: NoMatch) -> Result<Vec<String>> {
+    let options = MatchOptions {
+        case_sensitive: true,
+        require_literal_separator: true,
+        require_literal_leading_dot: true,
+    };
+    // The walk drops a leading `./`, so it's put back on each match.
+    let relative = pattern.trim_start_matches("./");
+    let prefix = &pattern[..pattern.len() - relative.len()];
+    // Walking with `require_literal_leading_dot` skips hidden entries even
+    // when the pattern asks for them, so they're filtered out afterwards.
+    let walk_options = MatchOptions {
+        require_literal_leading_dot: false,
+        ..options
+    };
+    // Something like a lone `[` isn't a pattern at all.
+    let (Ok(matcher), Ok(paths)) = (
+        Pattern::new(relative),
+        glob::glob_with(relative, walk_options),
+    ) else {
+        return Ok(vec![literal.to_owned()]);
+    };
+    let mut words = paths
+        .flatten()
+        .filter(|path| matcher.matches_path_with(path, options))
+        .map(|path| format!("{}{}", prefix, path.to_string_lossy()))
+        .collect::<Vec<_>>();
+
+    if words.is_empty() {
+        match nomatch {
+            NoMatch::PassThrough => words.push(literal.to_owned()),
+            NoMatch::Empty => {}
+            NoMatch::Error => bail!("no match: {}", literal),
+        }
+    }
+
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shell::load_state;
+
+    #[test]
+    fn expands_nested_braces() {
+        assert_eq!(expand_braces("a{b,c{d,e}}f"), ["abf", "acdf", "acef"]);
+        assert_eq!(expand_braces("{a,b}{1,2}"), ["a1", "a2", "b1", "b2"]);
+        assert_eq!(expand_braces("{x,{y,z}w}"), ["x", "yw", "zw"]);
+        assert_eq!(expand_braces("{a,}b"), ["ab", "b"]);
+    }
+
+    #[test]
+    fn expands_ranges() {
+        assert_eq!(expand_braces("{1..4}"), ["1", "2", "3", "4"]);
+        assert_eq!(expand_braces("{3..1}"), ["3", "2", "1"]);
+        assert_eq!(expand_braces("{-1..1}"), ["-1", "0", "1"]);
+        assert_eq!(expand_braces("{a..c}"), ["a", "b", "c"]);
+        assert_eq!(
+            expand_braces("v{1..2}.{x,y}"),
+            ["v1.x", "v1.y", "v2.x", "v2.y"]
+        );
+    }
+
+    #[test]
+    fn leaves_other_braces_alone() {
+        for word in ["{a}", "{", "a}", "{1..}", "{ab..c}", "{}"] {
+            assert_eq!(expand_braces(word), [word]);
+        }
+        assert_eq!(expand_braces("{x}{a,b}"), ["{x}a", "{x}b"]);
+    }
+
+    fn word(text: &str) -> Box<Arg> {
+        Box::new(Arg::String {
+            arg_string: text.to_owned(),
+        })
+    }
+
+    #[tokio::test]
+    async fn uses_default_for_unset_or_empty() {
+        let state = load_state(false).await.unwrap();
+        let colon = ParamOp::Default {
+            word: word("fallback"),
+            colon: true,
+        };
+        let plain = ParamOp::Default {
+            word: word("fallback"),
+            colon: false,
+        };
+
+        let name = "PSH_TEST_DEFAULT";
+        assert_eq!(eval_param(name, &colon, &state).await.unwrap(), "fallback");
+        assert_eq!(eval_param(name, &plain, &state).await.unwrap(), "fallback");
+
+        state.lock().unwrap().vars.set(name, String::new()).unwrap();
+        assert_eq!(eval_param(name, &colon, &state).await.unwrap(), "fallback");
+        assert_eq!(eval_param(name, &plain, &state).await.unwrap(), "");
+
+        state.lock().unwrap().vars.set(name, "set".into()).unwrap();
+        assert_eq!(eval_param(name, &colon, &state).await.unwrap(), "set");
+        assert_eq!(eval_param(name, &plain, &state).await.unwrap(), "set");
+    }
+
+    #[tokio::test]
+    async fn counts_characters() {
+        let state = load_state(false).await.unwrap();
+        let name = "PSH_TEST_LENGTH";
+        let length = ParamOp::Length;
+
+        assert_eq!(eval_param(name, &length, &state).await.unwrap(), "0");
+        state
+            .lock()
+            .unwrap()
+            .vars
+            .set(name, "héllo".into())
+            .unwrap();
+        assert_eq!(eval_param(name, &length, &state).await.unwrap(), "5");
+    }
+}
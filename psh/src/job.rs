@@ -0,0 +1,297 @@
+use std::{
+    fmt::Display,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use nix::{
+    errno::Errno,
+    sys::{
+        signal::{killpg, pthread_sigmask, SigSet, SigmaskHow, Signal},
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::{tcsetpgrp, Pid},
+};
+use tokio::sync::watch;
+
+use crate::state::State;
+
+/// Exit code reported for a job that was stopped rather than finished.
+pub const STOPPED_STATUS: i32 = 128 + Signal::SIGTSTP as i32;
+
+/// Exit code of a process killed by Ctrl-C.
+pub const INTERRUPTED_STATUS: i32 = 128 + Signal::SIGINT as i32;
+
+/// Counts the signals the shell waits on, so that waiting for them doesn't
+/// block a thread. Each ticks whenever its signal arrives.
+#[derive(Debug, Clone)]
+pub struct SignalCounts {
+    /// `SIGCHLD`: a child has exited, stopped or carried on.
+    pub children: watch::Receiver<u64>,
+    /// `SIGINT`
+    pub interrupts: watch::Receiver<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessStatus {
+    Running,
+    Stopped,
+    /// The exit code, or 128 plus the signal number if it was killed.
+    Exited(i32),
+}
+
+/// One stage of a job.
+#[derive(Debug)]
+pub struct Process {
+    /// `None` for a builtin, which has already finished by the time the job
+    /// is created.
+    pub pid: Option<Pid>,
+    pub status: ProcessStatus,
+}
+
+impl Process {
+    pub fn running(pid: Pid) -> Self {
+        Self {
+            pid: Some(pid),
+            status: ProcessStatus::Running,
+        }
+    }
+
+    pub fn finished(status: i32) -> Self {
+        Self {
+            pid: None,
+            status: ProcessStatus::Exited(status),
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self.status {
+            ProcessStatus::Exited(code) => code,
+            ProcessStatus::Stopped => STOPPED_STATUS,
+            ProcessStatus::Running => 0,
+        }
+    }
+}
+
+/// A pipeline started by the shell.
+#[derive(Debug)]
+pub struct Job {
+    pub id: usize,
+    /// The job's own process group, with job control. Otherwise its processes
+    /// stay in the shell's and this is just the first one's pid.
+    pub pgid: Pid,
+    pub command: String,
+    pub processes: Vec<Process>,
+    /// Whether the user has been told about the job's latest state.
+    pub notified: bool,
+}
+
+impl Job {
+    pub fn new(command: String, pgid: Pid, processes: Vec<Process>) -> Self {
+        Self {
+            id: 0,
+            pgid,
+            command,
+            processes,
+            notified: true,
+        }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.processes
+            .iter()
+            .all(|p| matches!(p.status, ProcessStatus::Exited(_)))
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        !self.is_completed()
+            && self
+                .processes
+                .iter()
+                .all(|p| p.status != ProcessStatus::Running)
+    }
+
+    /// The job's exit code, taken from its last process like other shells.
+    pub fn status(&self) -> i32 {
+        self.statuses().last().copied().unwrap_or(0)
+    }
+
+    /// The exit code of every stage, in pipeline order.
+    pub fn statuses(&self) -> Vec<i32> {
+        self.processes.iter().map(Process::exit_code).collect()
+    }
+
+    fn mark(&mut self, status: WaitStatus) {
+        let (pid, new_status) = match status {
+            WaitStatus::Exited(pid, code) => (pid, ProcessStatus::Exited(code)),
+            WaitStatus::Signaled(pid, signal, _) => {
+                (pid, ProcessStatus::Exited(128 + signal as i32))
+            }
+            WaitStatus::Stopped(pid, _) => (pid, ProcessStatus::Stopped),
+            WaitStatus::Continued(pid) => (pid, ProcessStatus::Running),
+            _ => return,
+        };
+
+        if let Some(process) = self.processes.iter_mut().find(|p| p.pid == Some(pid)) {
+            if process.status != new_status {
+                process.status = new_status;
+                self.notified = false;
+            }
+        }
+    }
+
+    /// Collects the status changes the job's processes have reported. Each
+    /// is waited on by pid, since without job control they share the shell's
+    /// process group with everything else it runs. Without `WNOHANG` in
+    /// `flags` this blocks until the first running one reports.
+    fn poll(&mut self, flags: WaitPidFlag) -> Result<()> {
+        let blocking = !flags.contains(WaitPidFlag::WNOHANG);
+        let pending = self
+            .processes
+            .iter()
+            .filter(|p| match p.status {
+                ProcessStatus::Running => true,
+                ProcessStatus::Stopped => !blocking,
+                ProcessStatus::Exited(_) => false,
+            })
+            .filter_map(|p| p.pid)
+            .take(if blocking { 1 } else { usize::MAX })
+            .collect::<Vec<_>>();
+
+        for pid in pending {
+            let status = loop {
+                match waitpid(pid, Some(flags)) {
+                    Err(Errno::EINTR) => continue,
+                    // Someone else reaped it.
+                    Err(Errno::ECHILD) => break WaitStatus::Exited(pid, 0),
+                    result => break result?,
+                }
+            };
+            self.mark(status);
+        }
+
+        Ok(())
+    }
+
+    /// Checks on the job without blocking.
+    pub fn update(&mut self) -> Result<()> {
+        self.poll(WaitPidFlag::WNOHANG | WaitPidFlag::WUNTRACED | WaitPidFlag::WCONTINUED)
+    }
+
+    /// Waits until the job has either finished or stopped, checking on it
+    /// each time `signals` says a child changed state. Without them, e.g. in
+    /// a subshell, this blocks in `waitpid` instead.
+    pub async fn wait(&mut self, signals: Option<SignalCounts>) -> Result<()> {
+        let Some(SignalCounts { mut children, .. }) = signals else {
+            while !self.is_completed() && !self.is_stopped() {
+                self.poll(WaitPidFlag::WUNTRACED)?;
+            }
+            return Ok(());
+        };
+
+        loop {
+            // Marking the count seen before checking means a child that
+            // changes state in between still wakes us.
+            children.borrow_and_update();
+            self.update()?;
+            if self.is_completed() || self.is_stopped() {
+                return Ok(());
+            }
+            children.changed().await?;
+        }
+    }
+
+    /// Sends `SIGCONT` to the whole group.
+    pub fn resume(&mut self) -> Result<()> {
+        killpg(self.pgid, Signal::SIGCONT)?;
+        for process in self.processes.iter_mut() {
+            if process.status == ProcessStatus::Stopped {
+                process.status = ProcessStatus::Running;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn state_label(&self) -> String {
+        if self.is_completed() {
+            match self.status() {
+                0 => String::from("Done"),
+                code => format!("Exit {}", code),
+            }
+        } else if self.is_stopped() {
+            String::from("Stopped")
+        } else {
+            String::from("Running")
+        }
+    }
+}
+
+impl Display for Job {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "[{}]  {:<24}{}",
+            self.id,
+            self.state_label(),
+            self.command
+        )
+    }
+}
+
+/// Makes `pgid` the terminal's foreground process group.
+///
+/// The shell is in the background while a job owns the terminal, and taking
+/// it back from there raises `SIGTTOU` unless the signal is blocked.
+pub fn give_terminal(pgid: Pid) -> Result<()> {
+    let mut sigttou = SigSet::empty();
+    sigttou.add(Signal::SIGTTOU);
+    let mut previous = SigSet::empty();
+    pthread_sigmask(SigmaskHow::SIG_BLOCK, Some(&sigttou), Some(&mut previous))?;
+    let result = tcsetpgrp(0, pgid);
+    pthread_sigmask(SigmaskHow::SIG_SETMASK, Some(&previous), None)?;
+    result?;
+
+    Ok(())
+}
+
+/// Runs `job` in the foreground and waits for it to finish or stop, sending
+/// it `SIGCONT` first if `resume` is set. Stopped jobs are moved to the job
+/// table. Returns the exit code of each stage.
+pub async fn wait_for_job(
+    mut job: Job,
+    resume: bool,
+    state: &Arc<Mutex<State>>,
+) -> Result<Vec<i32>> {
+    let (interactive, shell_pgid, signals) = {
+        let mut state = state.lock().unwrap();
+        state.foreground_pgid = Some(job.pgid);
+        (state.interactive, state.shell_pgid, state.signals.clone())
+    };
+    if interactive {
+        give_terminal(job.pgid)?;
+    }
+
+    let resumed = if resume { job.resume() } else { Ok(()) };
+    let waited = match resumed {
+        Ok(()) => job.wait(signals).await,
+        Err(e) => Err(e),
+    };
+
+    if interactive {
+        give_terminal(shell_pgid)?;
+    }
+    let mut state = state.lock().unwrap();
+    state.foreground_pgid = None;
+    waited?;
+
+    let statuses = job.statuses();
+    if job.is_stopped() {
+        job.notified = true;
+        let id = state.add_job(job);
+        eprintln!();
+        eprintln!("{}", state.jobs.iter().find(|j| j.id == id).unwrap());
+    }
+
+    Ok(statuses)
+}
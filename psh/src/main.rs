@@ -1,38 +1,96 @@
+mod arith;
+mod builtins;
 mod command;
+mod expand;
+mod job;
 mod parser;
+mod redirect;
 mod server;
 mod shell;
 mod state;
+mod subshell;
+mod vars;
 
-use std::sync::{Arc, Mutex};
+use std::{
+    env, fs,
+    io::{self, ErrorKind, Read},
+    process::exit,
+    sync::{Arc, Mutex},
+};
 
 use anyhow::Result;
-use nix::libc::SIGINT;
+use job::SignalCounts;
+use nix::{
+    libc::{SIGCHLD, SIGINT, SIGTSTP, SIGTTIN, SIGTTOU},
+    unistd::isatty,
+};
+use redirect::Io;
 use server::start_services;
-use shell::Pshell;
+use shell::{load_state, run_script, Pshell};
 use signal_hook_tokio::Signals;
 use state::State;
+use tokio::sync::watch;
 use tokio_stream::StreamExt;
 
 struct SigHandler {
     state: Arc<Mutex<State>>,
+    signals: Signals,
+    children: watch::Sender<u64>,
+    interrupts: watch::Sender<u64>,
 }
 
 impl SigHandler {
-    pub fn new(state: Arc<Mutex<State>>) -> Self {
-        Self { state }
+    /// Starts catching signals straight away, so that none are missed by
+    /// anything that goes on to wait for them through `state.signals`.
+    ///
+    /// A script leaves Ctrl-C and Ctrl-Z alone, so they stop it along with
+    /// whatever it's running, as they would in other shells.
+    pub fn new(state: Arc<Mutex<State>>, script: bool) -> Result<Self> {
+        // The job control signals are only caught so that they don't stop
+        // psh itself; its jobs get them straight from the terminal.
+        let signals = if script {
+            Signals::new([SIGCHLD, SIGTTIN, SIGTTOU])?
+        } else {
+            Signals::new([SIGCHLD, SIGINT, SIGTSTP, SIGTTIN, SIGTTOU])?
+        };
+        let (children, children_rx) = watch::channel(0);
+        let (interrupts, interrupts_rx) = watch::channel(0);
+        state.lock().unwrap().signals = Some(SignalCounts {
+            children: children_rx,
+            interrupts: interrupts_rx,
+        });
+        Ok(Self {
+            state,
+            signals,
+            children,
+            interrupts,
+        })
     }
 
     pub async fn handle_signals(&mut self) -> Result<()> {
-        let mut signals = Signals::new(&[SIGINT])?;
-        signals.handle();
-        while let Some(_sigint) = signals.next().await {
-            let id = self.state.lock().unwrap().running_pid;
-            if let Some(id) = id {
-                let pid = nix::unistd::Pid::from_raw(id as i32);
-                if let Err(e) = nix::sys::signal::kill(pid, nix::sys::signal::SIGINT) {
-                    eprintln!("Couldn't send signal! {}", e);
+        while let Some(signal) = self.signals.next().await {
+            match signal {
+                SIGCHLD => {
+                    // Keep the job table current for anyone looking at it
+                    // before the next prompt, e.g. over gRPC.
+                    self.state.lock().unwrap().check_children();
+                    self.children.send_modify(|count| *count += 1);
                 }
+                SIGINT => {
+                    // Without job control, jobs are in psh's own process
+                    // group, so they got Ctrl-C from the terminal already.
+                    let (interactive, pgid) = {
+                        let state = self.state.lock().unwrap();
+                        (state.interactive, state.foreground_pgid)
+                    };
+                    if let Some(pgid) = pgid.filter(|_| interactive) {
+                        if let Err(e) = nix::sys::signal::killpg(pgid, nix::sys::signal::SIGINT) {
+                            eprintln!("Couldn't send signal! {}", e);
+                        }
+                    }
+                    self.interrupts.send_modify(|count| *count += 1);
+                }
+                _ => {}
             }
         }
 
@@ -40,14 +98,142 @@ impl SigHandler {
     }
 }
 
+/// Commands to run without prompting for them.
+struct Script {
+    text: String,
+    /// What to call the script in error messages.
+    source: String,
+    /// `$0`
+    name: String,
+    /// `$1` onwards.
+    args: Vec<String>,
+}
+
+/// psh's command line.
+struct Args {
+    /// Whether to run the rc files. Only interactive shells do.
+    rc: bool,
+    /// `None` means an interactive shell.
+    script: Option<Script>,
+}
+
+impl Args {
+    /// Reads `[--norc] [-c command [name [args...]] | script [args...]]`,
+    /// falling back to whatever's piped to psh.
+    fn parse() -> Result<Args> {
+        let mut args = env::args();
+        let argv0 = args.next().unwrap_or_else(|| String::from("psh"));
+        let mut args = args.collect::<Vec<_>>().into_iter().peekable();
+
+        let mut rc = true;
+        while let Some(option) = args.next_if(|arg| arg.starts_with("--")) {
+            match option.as_str() {
+                "--norc" => rc = false,
+                // Everything after a bare -- is the script and its arguments.
+                "--" => break,
+                _ => {
+                    eprintln!("psh: {}: invalid option", option);
+                    eprintln!(
+                        "Usage: psh [--norc] [-c command [name [args...]] | script [args...]]"
+                    );
+                    exit(2);
+                }
+            }
+        }
+
+        Ok(Args {
+            rc,
+            script: Script::from_args(argv0, args)?,
+        })
+    }
+}
+
+impl Script {
+    /// Works out what to run from psh's arguments after its options: `-c
+    /// command [name [args...]]`, `script [args...]`, or whatever's piped to
+    /// it. `None` means an interactive shell.
+    fn from_args(argv0: String, mut args: impl Iterator<Item = String>) -> Result<Option<Script>> {
+        Ok(match args.next() {
+            Some(flag) if flag == "-c" => {
+                let Some(text) = args.next() else {
+                    eprintln!("psh: -c: option requires an argument");
+                    exit(2);
+                };
+                Some(Script {
+                    text,
+                    source: String::from("-c"),
+                    name: args.next().unwrap_or(argv0),
+                    args: args.collect(),
+                })
+            }
+            Some(path) => {
+                let text = match fs::read_to_string(&path) {
+                    Ok(text) => text,
+                    Err(e) => {
+                        eprintln!("psh: {}: {}", path, e);
+                        exit(if e.kind() == ErrorKind::NotFound {
+                            127
+                        } else {
+                            126
+                        });
+                    }
+                };
+                Some(Script {
+                    text,
+                    source: path.clone(),
+                    name: path,
+                    args: args.collect(),
+                })
+            }
+            None if !isatty(0).unwrap_or(false) => {
+                let mut text = String::new();
+                io::stdin().read_to_string(&mut text)?;
+                Some(Script {
+                    text,
+                    source: String::from("stdin"),
+                    name: argv0,
+                    args: vec![],
+                })
+            }
+            None => None,
+        })
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let mut shell = Pshell::new().await?;
-    tokio::spawn(start_services(shell.get_state_ref()));
-    let sighandler = Box::leak(Box::new(SigHandler::new(shell.get_state_ref())));
+    let args = Args::parse()?;
+    let Some(script) = args.script else {
+        let mut shell = Pshell::new(args.rc).await?;
+        tokio::spawn(start_services(shell.get_state_ref()));
+        let sighandler = Box::leak(Box::new(SigHandler::new(shell.get_state_ref(), false)?));
+        tokio::spawn(sighandler.handle_signals());
+
+        shell.run().await?;
+
+        return Ok(());
+    };
+
+    // Scripts don't do job control, so they leave the terminal alone and
+    // their jobs stay in psh's process group.
+    let state = load_state(false).await?;
+    {
+        let mut state = state.lock().unwrap();
+        state.shell_name = script.name;
+        state.positional = script.args;
+    }
+    tokio::spawn(start_services(Arc::clone(&state)));
+    let sighandler = Box::leak(Box::new(SigHandler::new(Arc::clone(&state), true)?));
     tokio::spawn(sighandler.handle_signals());
 
-    shell.run().await?;
+    let status = run_script(
+        &script.text,
+        &script.source,
+        Io::Inherit,
+        Io::Inherit,
+        &state,
+    )
+    .await?;
 
-    Ok(())
+    exit(status);
 }
@@ -1,30 +1,474 @@
 #![allow(dead_code)]
+use std::{collections::HashMap, fmt::Display, ops::Range};
+
 use anyhow::Result;
 use pest::{
+    error::{ErrorVariant, InputLocation},
     iterators::{Pair, Pairs},
     Parser,
 };
 use pest_derive::Parser;
 
 use crate::{
-    command::{Arg, Command},
-    state::Alias,
+    arith::{climb, BinOp, Expr, UnaryOp},
+    command::{Arg, Assignment, CaseItem, Command},
+    expand::ParamOp,
+    redirect::{Redirect, RedirectMode},
+    state::{Alias, Function},
 };
 
 #[derive(Parser)]
 #[grammar = "cli.pest"]
 struct CliParser;
 
-pub fn parse_pest(input_line: &str) -> Result<Command> {
-    let parsed = CliParser::parse(Rule::bin, input_line)?;
-    let parsed_len = parsed.as_str().len();
-    if parsed_len < input_line.len() {
-        anyhow::bail!("Syntax error");
+/// Returned when the input stops before a construct that spans several lines
+/// is finished, so the caller can read another line and try again. If there
+/// are no more lines, `error` says what was left open.
+#[derive(Debug)]
+pub struct Incomplete {
+    pub error: ParseError,
+}
+
+impl Display for Incomplete {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl std::error::Error for Incomplete {}
+
+/// Input that doesn't fit the grammar, and where it stopped making sense.
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    /// Byte offsets of the offending text in the parsed input.
+    pub span: Range<usize>,
+    /// 1-based, counting from the start of the parsed input.
+    pub line: usize,
+    /// 1-based, in characters.
+    pub column: usize,
+    /// The line the error is on.
+    pub text: String,
+    /// What would have fitted there, e.g. "a command" or "`fi`".
+    pub expected: Vec<String>,
+    pub message: String,
+}
+
+impl ParseError {
+    fn new(input: &str, span: Range<usize>, expected: Vec<String>, message: String) -> Self {
+        let line_start = input[..span.start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = input[span.start..]
+            .find('\n')
+            .map_or(input.len(), |i| span.start + i);
+
+        Self {
+            line: input[..span.start].matches('\n').count() + 1,
+            column: input[line_start..span.start].chars().count() + 1,
+            text: input[line_start..line_end].to_owned(),
+            span,
+            expected,
+            message,
+        }
+    }
+
+    /// Something missing after `end`, pointing at the last word before it.
+    fn at_end(input: &str, end: usize, expected: Vec<String>) -> Self {
+        let trimmed = &input[..end];
+        let start = trimmed
+            .char_indices()
+            .rev()
+            .find(|(_, c)| c.is_whitespace())
+            .map_or(0, |(i, c)| i + c.len_utf8());
+        let message = format!("unexpected end of input, expected {}", one_of(&expected));
+
+        Self::new(input, start..trimmed.len(), expected, message)
+    }
+
+    /// A quote that's never closed, from where it opens.
+    fn unterminated_quote(input: &str, span: Range<usize>) -> Self {
+        let message = String::from("unterminated quote");
+
+        Self::new(input, span, vec![], message)
+    }
+
+    /// Whatever's at `start` where one of `expected` should have been.
+    fn unexpected(input: &str, start: usize, expected: &[Rule]) -> Self {
+        // The offending word, or just the character if it's an operator.
+        let rest = &input[start..];
+        let len = match rest.find(|c: char| c.is_whitespace() || "|&;()<>".contains(c)) {
+            Some(0) => rest.chars().next().map_or(0, char::len_utf8),
+            Some(len) => len,
+            None => rest.len(),
+        };
+        let found = &rest[..len];
+
+        // If the command could have ended there, the problem is what's there
+        // rather than something missing, and the list of what else could
+        // have gone there isn't much help.
+        let described = describe_rules(expected);
+        let message = if expected.contains(&Rule::EOI) || described.is_empty() {
+            format!("unexpected `{}`", found)
+        } else {
+            format!("unexpected `{}`, expected {}", found, one_of(&described))
+        };
+
+        Self::new(input, start..start + len, described, message)
+    }
+
+    /// The offending line with carets under the problem.
+    pub fn excerpt(&self) -> String {
+        let start = self
+            .text
+            .char_indices()
+            .nth(self.column - 1)
+            .map_or(self.text.len(), |(i, _)| i);
+        let end = (start + self.span.len()).min(self.text.len());
+        let width = self.text[start..end].chars().count().max(1);
+
+        format!(
+            "    {}\n    {}{}",
+            self.text.replace('\t', " "),
+            " ".repeat(self.column - 1),
+            "^".repeat(width)
+        )
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "syntax error: {}", self.message)?;
+        write!(f, "{}", self.excerpt())
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// The [`ParseError`] behind a failure from [`parse_pest`], whether or not
+/// more input could have fixed it.
+pub fn parse_error(e: &anyhow::Error) -> Option<&ParseError> {
+    match e.downcast_ref::<Incomplete>() {
+        Some(incomplete) => Some(&incomplete.error),
+        None => e.downcast_ref::<ParseError>(),
+    }
+}
+
+/// "a", "a or b", "a, b or c".
+fn one_of(items: &[String]) -> String {
+    match items {
+        [] => String::from("something else"),
+        [only] => only.clone(),
+        [rest @ .., last] => format!("{} or {}", rest.join(", "), last),
+    }
+}
+
+/// What the user would call each of `rules`, without repeats.
+fn describe_rules(rules: &[Rule]) -> Vec<String> {
+    let mut described = Vec::<String>::new();
+    for description in rules.iter().filter_map(describe) {
+        if !described.contains(&description) {
+            described.push(description);
+        }
+    }
+
+    described
+}
+
+fn describe(rule: &Rule) -> Option<String> {
+    if let Some(keyword) = closing_keyword(rule) {
+        return Some(format!("`{}`", keyword));
+    }
+
+    Some(String::from(match rule {
+        Rule::invocation
+        | Rule::command
+        | Rule::assignment
+        | Rule::pipeline
+        | Rule::bin
+        | Rule::list
+        | Rule::if_clause
+        | Rule::while_clause
+        | Rule::for_clause
+        | Rule::case_clause
+        | Rule::function_def
+        | Rule::brace_group
+        | Rule::subshell
+        | Rule::group
+        | Rule::kw_if
+        | Rule::kw_while
+        | Rule::kw_until
+        | Rule::kw_for
+        | Rule::kw_case
+        | Rule::kw_function
+        | Rule::kw_lbrace => "a command",
+        Rule::arg
+        | Rule::word
+        | Rule::escaped
+        | Rule::dollar
+        | Rule::single_quoted
+        | Rule::double_quoted
+        | Rule::var
+        | Rule::param
+        | Rule::subcmd
+        | Rule::procsub
+        | Rule::arith => "an argument",
+        Rule::redirect | Rule::redirect_op => "a redirection",
+        Rule::binop | Rule::and | Rule::or => "`&&` or `||`",
+        Rule::separator | Rule::sequence | Rule::background => "`;` or `&`",
+        Rule::assign_name => "a name",
+        Rule::param_name => "a parameter name",
+        Rule::case_item | Rule::case_pattern => "a pattern",
+        Rule::arith_expr
+        | Rule::arith_operand
+        | Rule::arith_assign
+        | Rule::arith_num
+        | Rule::arith_var
+        | Rule::arith_group
+        | Rule::arith_unary => "a number or variable",
+        Rule::arith_op => "an arithmetic operator",
+        _ => return None,
+    }))
+}
+
+/// Where the quote is that's still open at the end of `input`, if any.
+fn unclosed_quote(input: &str) -> Option<usize> {
+    let mut open: Option<(char, usize)> = None;
+    let mut word_start = true;
+    let mut chars = input.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match open {
+            Some((quote, _)) if c == quote => open = None,
+            Some(('"', _)) if c == '\\' => {
+                chars.next();
+            }
+            Some(_) => {}
+            None => match c {
+                '\\' => {
+                    chars.next();
+                }
+                '#' if word_start => {
+                    chars.find(|(_, c)| *c == '\n');
+                    word_start = true;
+                    continue;
+                }
+                '\'' | '"' => open = Some((c, i)),
+                _ => {}
+            },
+        }
+        word_start = c.is_whitespace() || "|&;()".contains(c);
+    }
+
+    open.map(|(_, start)| start)
+}
+
+/// Here-document bodies, keyed by the byte offset of their redirect in the
+/// command text.
+type HereDocs = HashMap<usize, String>;
+
+/// A command line with its here-document bodies taken out.
+struct CommandText {
+    text: String,
+    heredocs: HereDocs,
+    /// Where each line of `text` starts in the original input.
+    line_starts: Vec<usize>,
+}
+
+impl CommandText {
+    /// Where `position` in the command text was in the original input.
+    fn original_position(&self, position: usize) -> usize {
+        let line = self.text[..position].matches('\n').count();
+        let line_start = self.text[..position].rfind('\n').map_or(0, |i| i + 1);
+
+        self.line_starts[line] + position - line_start
+    }
+}
+
+/// Parses a command line, which may span several lines. `None` if there's
+/// nothing to run.
+pub fn parse_pest(input: &str) -> Result<Option<Command>> {
+    let command_text = collect_heredocs(input)?;
+    let mut parsed = match CliParser::parse(Rule::program, &command_text.text) {
+        Ok(parsed) => parsed,
+        Err(e) => return Err(parse_failure(&e, input, &command_text)),
+    };
+
+    let program = parsed.next().unwrap();
+    match program.into_inner().find(|p| p.as_rule() == Rule::list) {
+        Some(list) => Ok(Some(recurse_commands(list, &command_text.heredocs)?)),
+        None => Ok(None),
+    }
+}
+
+/// Works out what's wrong with `input` from where pest gave up on it. Errors
+/// at the very end, e.g. in an `if` without its `fi`, are [`Incomplete`].
+fn parse_failure(
+    e: &pest::error::Error<Rule>,
+    input: &str,
+    command_text: &CommandText,
+) -> anyhow::Error {
+    let position = match e.location {
+        InputLocation::Pos(position) => position,
+        InputLocation::Span((start, _)) => start,
+    };
+    let expected = match &e.variant {
+        ErrorVariant::ParsingError { positives, .. } => positives.clone(),
+        ErrorVariant::CustomError { .. } => vec![],
+    };
+    let rest = command_text.text[position..].trim();
+    let end = command_text.original_position(command_text.text.trim_end().len());
+
+    if let Some(quote) = unclosed_quote(&command_text.text) {
+        if quote == position || rest.is_empty() {
+            let error =
+                ParseError::unterminated_quote(input, command_text.original_position(quote)..end);
+            return Incomplete { error }.into();
+        }
     }
 
-    let pipe = parsed.into_iter().next().unwrap();
+    match rest {
+        "" => {
+            let keywords = expected
+                .iter()
+                .filter_map(closing_keyword)
+                .map(|keyword| format!("`{}`", keyword))
+                .collect::<Vec<_>>();
+            let expected = if keywords.is_empty() {
+                vec![String::from("a command")]
+            } else {
+                keywords
+            };
+            let error = ParseError::at_end(input, end, expected);
+            Incomplete { error }.into()
+        }
+        // A line ending in a backslash carries on to the next one.
+        "\\" => {
+            let error = ParseError::at_end(input, end, vec![String::from("the rest of the line")]);
+            Incomplete { error }.into()
+        }
+        _ => ParseError::unexpected(input, command_text.original_position(position), &expected)
+            .into(),
+    }
+}
 
-    recurse_commands(pipe)
+/// The keyword `rule` matches, if it's one that carries on or closes a block.
+fn closing_keyword(rule: &Rule) -> Option<&'static str> {
+    Some(match rule {
+        Rule::kw_then => "then",
+        Rule::kw_elif => "elif",
+        Rule::kw_else => "else",
+        Rule::kw_fi => "fi",
+        Rule::kw_do => "do",
+        Rule::kw_done => "done",
+        Rule::kw_in => "in",
+        Rule::kw_esac => "esac",
+        Rule::kw_rbrace => "}",
+        Rule::subshell_end => ")",
+        _ => return None,
+    })
+}
+
+/// Splits here-document bodies out of `input`, leaving the command text to
+/// parse.
+fn collect_heredocs(input: &str) -> Result<CommandText> {
+    let mut text = String::new();
+    let mut heredocs = HereDocs::new();
+    let mut line_starts = vec![];
+    let mut lines = input.split_inclusive('\n').scan(0, |offset, line| {
+        let start = *offset;
+        *offset += line.len();
+        let line = line.strip_suffix('\n').unwrap_or(line);
+        Some((start, line.strip_suffix('\r').unwrap_or(line)))
+    });
+
+    while let Some((line_start, line)) = lines.next() {
+        if !text.is_empty() {
+            text.push('\n');
+        }
+        let offset = text.len();
+        text.push_str(line);
+        line_starts.push(line_start);
+
+        let Ok(scanned) = CliParser::parse(Rule::heredoc_scan, line) else {
+            continue;
+        };
+        for redirect in scanned.filter(|p| p.as_rule() == Rule::redirect) {
+            let span = redirect.as_span();
+            let mut pairs = redirect.into_inner();
+            let op = pairs.next().unwrap().into_inner().last().unwrap();
+            let strip_tabs = match op.as_rule() {
+                Rule::heredoc => false,
+                Rule::heredoc_strip => true,
+                _ => continue,
+            };
+            let (delimiter, _) = heredoc_delimiter(pairs.next().unwrap());
+
+            let mut body = String::new();
+            loop {
+                let Some((_, line)) = lines.next() else {
+                    let expected = format!("here-document delimiter `{}`", delimiter);
+                    let error = ParseError::at_end(input, line_start + span.end(), vec![expected]);
+                    return Err(Incomplete { error }.into());
+                };
+                let line = if strip_tabs {
+                    line.trim_start_matches('\t')
+                } else {
+                    line
+                };
+                if line == delimiter {
+                    break;
+                }
+                body.push_str(line);
+                body.push('\n');
+            }
+            heredocs.insert(offset + span.start(), body);
+        }
+    }
+
+    Ok(CommandText {
+        text,
+        heredocs,
+        line_starts,
+    })
+}
+
+/// The word that ends a here-document, with its quotes removed, and whether
+/// any of it was quoted.
+fn heredoc_delimiter(arg: Pair<Rule>) -> (String, bool) {
+    let mut delimiter = String::new();
+    let mut quoted = false;
+    for part in arg.into_inner() {
+        let text = part.as_str();
+        match part.as_rule() {
+            Rule::single_quoted | Rule::double_quoted => {
+                delimiter.push_str(&text[1..text.len() - 1]);
+                quoted = true;
+            }
+            Rule::escaped => {
+                delimiter.push_str(&text[1..]);
+                quoted = true;
+            }
+            _ => delimiter.push_str(text),
+        }
+    }
+
+    (delimiter, quoted)
+}
+
+/// Builds the argument a here-document feeds to its command. Bodies are
+/// expanded unless the delimiter was quoted.
+fn heredoc_body(body: &str, quoted: bool) -> Result<Arg> {
+    if quoted {
+        return Ok(Arg::String {
+            arg_string: body.to_owned(),
+        });
+    }
+
+    let parsed = CliParser::parse(Rule::heredoc_body, body)?;
+    let parts = parsed
+        .flat_map(|body| body.into_inner())
+        .filter(|part| part.as_rule() != Rule::EOI)
+        .map(|part| recurse_arg_part(part, &HereDocs::new()))
+        .collect::<Result<_>>()?;
+
+    Ok(Arg::Compound { parts })
 }
 
 fn get_rule<'a>(pairs: &'a [Pair<Rule>], rule: Rule) -> Result<Pair<'a, Rule>> {
@@ -49,7 +493,6 @@ fn get_children(pair: Pairs<Rule>) -> Result<Vec<Pair<Rule>>> {
         .next()
         .ok_or_else(|| anyhow::anyhow!("no pairs in parsed"))?
         .into_inner()
-        .into_iter()
         .collect())
 }
 
@@ -65,12 +508,14 @@ pub fn parse_alias(alias_line: &str) -> Result<Alias> {
     let alias = get_rule(&definition, Rule::alias)?;
     let invocation = get_rule(&definition, Rule::invocation)?
         .into_inner()
-        .into_iter()
         .collect::<Vec<_>>();
     let command = get_rule(&invocation, Rule::command)?;
     let args = get_rules(&invocation, Rule::arg);
 
-    let args = args.into_iter().map(recurse_args).collect::<Result<_>>()?;
+    let args = args
+        .into_iter()
+        .map(|arg| recurse_args(arg, &HereDocs::new()))
+        .collect::<Result<_>>()?;
 
     let alias = Alias {
         alias: alias.as_str().to_owned(),
@@ -81,31 +526,198 @@ pub fn parse_alias(alias_line: &str) -> Result<Alias> {
     Ok(alias)
 }
 
-fn recurse_args(pair: Pair<Rule>) -> Result<Arg> {
-    let pair = pair.into_inner().into_iter().next().unwrap();
+/// Builds an argument from the parts written next to each other, like
+/// `--out="$dir"/log`.
+fn recurse_args(pair: Pair<Rule>, heredocs: &HereDocs) -> Result<Arg> {
+    let mut parts = pair
+        .into_inner()
+        .map(|part| recurse_arg_part(part, heredocs))
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(if parts.len() == 1 {
+        parts.pop().unwrap()
+    } else {
+        Arg::Compound { parts }
+    })
+}
+
+/// A double-quoted string. Backslashes only escape the characters that would
+/// otherwise mean something inside the quotes.
+fn recurse_double_quoted(pair: Pair<Rule>, heredocs: &HereDocs) -> Result<Arg> {
+    let mut parts = pair
+        .into_inner()
+        .map(|part| {
+            Ok(match part.as_rule() {
+                Rule::double_escaped => {
+                    let text = part.as_str();
+                    let escaped = &text[1..];
+                    Arg::String {
+                        arg_string: if escaped == "\n" {
+                            String::new()
+                        } else if ["$", "\"", "\\", "`"].contains(&escaped) {
+                            escaped.to_owned()
+                        } else {
+                            text.to_owned()
+                        },
+                    }
+                }
+                _ => recurse_arg_part(part, heredocs)?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(match parts.as_slice() {
+        [Arg::String { .. }] => parts.pop().unwrap(),
+        _ => Arg::Compound { parts },
+    })
+}
+
+/// Whether an unquoted word needs tilde, brace or pathname expansion.
+fn needs_expansion(word: &str) -> bool {
+    word.starts_with('~') || word.contains(['{', '*', '?', '['])
+}
+
+fn recurse_param(pair: Pair<Rule>, heredocs: &HereDocs) -> Result<Arg> {
+    let parts = pair.into_inner().collect::<Vec<_>>();
+    let name = get_rule(&parts, Rule::param_name)?.as_str().to_owned();
+    if get_rule(&parts, Rule::param_length).is_ok() {
+        return Ok(Arg::Param {
+            name,
+            op: ParamOp::Length,
+        });
+    }
+    let Ok(op) = get_rule(&parts, Rule::param_op) else {
+        return Ok(Arg::Param {
+            name,
+            op: ParamOp::Value,
+        });
+    };
+
+    let word = get_rule(&parts, Rule::param_word)?
+        .into_inner()
+        .map(|part| recurse_arg_part(part, heredocs))
+        .collect::<Result<_>>()?;
+    let word = Box::new(Arg::Compound { parts: word });
+    let colon = op.as_str().starts_with(':');
+
+    let op = match op.as_str().trim_start_matches(':') {
+        "-" => ParamOp::Default { word, colon },
+        "?" => ParamOp::Error { word, colon },
+        "+" => ParamOp::Alternative { word, colon },
+        "%" => ParamOp::RemoveSuffix {
+            pattern: word,
+            longest: false,
+        },
+        "%%" => ParamOp::RemoveSuffix {
+            pattern: word,
+            longest: true,
+        },
+        "#" => ParamOp::RemovePrefix {
+            pattern: word,
+            longest: false,
+        },
+        _ => ParamOp::RemovePrefix {
+            pattern: word,
+            longest: true,
+        },
+    };
+
+    Ok(Arg::Param { name, op })
+}
+
+/// Parses an arithmetic expression on its own, as given to `let` and `math`.
+pub fn parse_arith(text: &str) -> Result<Expr> {
+    let mut parsed = CliParser::parse(Rule::arith_line, text)?;
+    let expr = parsed.next().unwrap().into_inner().next().unwrap();
+
+    recurse_arith(expr)
+}
+
+fn recurse_arith(pair: Pair<Rule>) -> Result<Expr> {
     Ok(match pair.as_rule() {
+        Rule::arith_expr => {
+            let mut pairs = pair.into_inner();
+            let first = pairs.next().unwrap();
+            if first.as_rule() == Rule::arith_assign {
+                return recurse_arith(first);
+            }
+            let first = recurse_arith(first)?;
+            let mut rest = vec![];
+            while let (Some(op), Some(operand)) = (pairs.next(), pairs.next()) {
+                rest.push((BinOp::from_symbol(op.as_str()), recurse_arith(operand)?));
+            }
+            climb(first, rest)
+        }
+        Rule::arith_assign => {
+            let mut pairs = pair.into_inner();
+            let name = pairs.next().unwrap().as_str().to_owned();
+            let value = recurse_arith(pairs.next().unwrap())?;
+            Expr::Assign {
+                name,
+                value: Box::new(value),
+            }
+        }
+        Rule::arith_operand => {
+            let mut pairs = pair.into_inner().collect::<Vec<_>>();
+            let mut expr = recurse_arith(pairs.pop().unwrap())?;
+            for op in pairs.into_iter().rev() {
+                expr = Expr::Unary {
+                    op: UnaryOp::from_symbol(op.as_str()),
+                    expr: Box::new(expr),
+                };
+            }
+            expr
+        }
+        Rule::arith_group => {
+            let expr = recurse_arith(pair.into_inner().next().unwrap())?;
+            Expr::Group(Box::new(expr))
+        }
+        Rule::arith_num => Expr::Num(
+            pair.as_str()
+                .parse()
+                .map_err(|_| anyhow::anyhow!("{}: number too large", pair.as_str()))?,
+        ),
+        Rule::arith_var => Expr::Var(pair.as_str().trim_start_matches('$').to_owned()),
+        _ => unreachable!(),
+    })
+}
+
+fn recurse_arg_part(pair: Pair<Rule>, heredocs: &HereDocs) -> Result<Arg> {
+    Ok(match pair.as_rule() {
+        Rule::arith => Arg::Arith {
+            expr: recurse_arith(pair.into_inner().next().unwrap())?,
+        },
         Rule::subcmd => {
-            let pipe = pair.into_inner().into_iter().next().unwrap();
-            let pipe = recurse_commands(pipe)?;
+            let pipe = pair.into_inner().next().unwrap();
+            let pipe = recurse_commands(pipe, heredocs)?;
             Arg::Subcommand { command: pipe }
         }
+        Rule::procsub => {
+            let mut pairs = pair.into_inner();
+            let writable = pairs.next().unwrap().as_rule() == Rule::procsub_write;
+            let command = recurse_commands(pairs.next().unwrap(), heredocs)?;
+            Arg::ProcessSubstitution { command, writable }
+        }
         Rule::var => Arg::Env {
-            var_name: pair
-                .into_inner()
-                .into_iter()
-                .next()
-                .unwrap()
-                .as_str()
-                .to_owned(),
+            var_name: pair.into_inner().next().unwrap().as_str().to_owned(),
         },
-        Rule::literal => Arg::String {
-            arg_string: pair
-                .into_inner()
-                .into_iter()
-                .next()
-                .unwrap()
-                .as_str()
-                .to_owned(),
+        Rule::single_quoted => Arg::String {
+            arg_string: pair.into_inner().as_str().to_owned(),
+        },
+        Rule::double_quoted => recurse_double_quoted(pair, heredocs)?,
+        // A backslash before a newline just joins the lines.
+        Rule::escaped if pair.as_str() == "\\\n" => Arg::String {
+            arg_string: String::new(),
+        },
+        Rule::escaped => Arg::String {
+            arg_string: pair.as_str()[1..].to_owned(),
+        },
+        Rule::dollar | Rule::double_text | Rule::param_text | Rule::heredoc_text => Arg::String {
+            arg_string: pair.as_str().to_owned(),
+        },
+        Rule::param => recurse_param(pair, heredocs)?,
+        Rule::word if needs_expansion(pair.as_str()) => Arg::Word {
+            word: pair.as_str().to_owned(),
         },
         Rule::word => Arg::String {
             arg_string: pair.as_str().to_owned(),
@@ -114,40 +726,122 @@ fn recurse_args(pair: Pair<Rule>) -> Result<Arg> {
     })
 }
 
-pub fn recurse_commands(pair: Pair<Rule>) -> Result<Command> {
+fn recurse_assignment(pair: Pair<Rule>, heredocs: &HereDocs) -> Result<Assignment> {
+    let mut pairs = pair.into_inner();
+    let name = pairs.next().unwrap().as_str().to_owned();
+    let value = match pairs.next() {
+        Some(value) => recurse_args(value, heredocs)?,
+        None => Arg::String {
+            arg_string: String::new(),
+        },
+    };
+
+    Ok(Assignment { name, value })
+}
+
+fn recurse_redirect(pair: Pair<Rule>, heredocs: &HereDocs) -> Result<Vec<Redirect>> {
+    let position = pair.as_span().start();
+    let mut pairs = pair.into_inner();
+    let op = pairs.next().unwrap().into_inner().collect::<Vec<_>>();
+    let target = pairs.next().unwrap();
+
+    if matches!(
+        op.last().unwrap().as_rule(),
+        Rule::heredoc | Rule::heredoc_strip
+    ) {
+        let (_, quoted) = heredoc_delimiter(target);
+        let body = heredocs
+            .get(&position)
+            .ok_or_else(|| anyhow::anyhow!("Missing here-document body"))?;
+        let fd = match get_rule(&op, Rule::fd) {
+            Ok(fd) => fd.as_str().parse()?,
+            Err(_) => 0,
+        };
+        return Ok(vec![Redirect {
+            fd,
+            mode: RedirectMode::HereDoc,
+            target: heredoc_body(body, quoted)?,
+        }]);
+    }
+
+    let target = recurse_args(target, heredocs)?;
+
+    if op.iter().any(|p| p.as_rule() == Rule::all) {
+        return Ok(vec![
+            Redirect {
+                fd: 1,
+                mode: RedirectMode::Write,
+                target,
+            },
+            Redirect {
+                fd: 2,
+                mode: RedirectMode::Duplicate,
+                target: Arg::String {
+                    arg_string: String::from("1"),
+                },
+            },
+        ]);
+    }
+
+    let mode = match op.last().unwrap().as_rule() {
+        Rule::herestring => RedirectMode::HereString,
+        Rule::read => RedirectMode::Read,
+        Rule::append => RedirectMode::Append,
+        Rule::duplicate => RedirectMode::Duplicate,
+        Rule::write => RedirectMode::Write,
+        _ => unreachable!(),
+    };
+    let fd = match get_rule(&op, Rule::fd) {
+        Ok(fd) => fd.as_str().parse()?,
+        Err(_) => mode.default_fd(),
+    };
+
+    Ok(vec![Redirect { fd, mode, target }])
+}
+
+pub fn recurse_commands(pair: Pair<Rule>, heredocs: &HereDocs) -> Result<Command> {
     match pair.as_rule() {
-        Rule::subcmd => todo!(),
         Rule::invocation => {
             let pairs = pair.into_inner().collect::<Vec<_>>();
-            let cmd = pairs
+            let command = pairs
                 .iter()
                 .find(|p| p.as_rule() == Rule::command)
-                .cloned()
-                .unwrap();
+                .map(|command| command.as_str().to_owned())
+                .unwrap_or_default();
+            let assignments = get_rules(&pairs, Rule::assignment)
+                .into_iter()
+                .map(|assignment| recurse_assignment(assignment, heredocs))
+                .collect::<Result<Vec<_>>>()?;
             let args = pairs
                 .iter()
                 .filter(|p| p.as_rule() == Rule::arg)
                 .cloned()
-                .map(recurse_args)
+                .map(|arg| recurse_args(arg, heredocs))
                 .collect::<Result<Vec<_>>>()?;
+            let mut redirects = vec![];
+            for redirect in get_rules(&pairs, Rule::redirect) {
+                redirects.append(&mut recurse_redirect(redirect, heredocs)?);
+            }
             Ok(Command::Simple {
-                command: cmd.as_str().to_owned(),
+                assignments,
+                command,
                 args,
+                redirects,
             })
         }
         Rule::bin => {
             let mut pairs = pair.into_inner().collect::<Vec<_>>();
             assert!(pairs.len() % 2 == 1);
             let right = pairs.pop().unwrap();
-            let mut right = recurse_commands(right)?;
+            let mut right = recurse_commands(right, heredocs)?;
             pairs.reverse();
 
             for chunk in pairs.chunks(2) {
                 let chunk = chunk.to_vec();
-                let op = chunk.get(0).unwrap().clone();
+                let op = chunk.first().unwrap().clone();
                 let left = chunk.get(1).unwrap().clone();
-                let left = recurse_commands(left)?;
-                let op = op.into_inner().into_iter().next().unwrap();
+                let left = recurse_commands(left, heredocs)?;
+                let op = op.into_inner().next().unwrap();
                 right = match op.as_rule() {
                     Rule::and => Command::And {
                         left: Box::new(left),
@@ -163,32 +857,437 @@ pub fn recurse_commands(pair: Pair<Rule>) -> Result<Command> {
 
             Ok(right)
         }
+        Rule::list => {
+            let mut commands = vec![];
+            for child in pair.into_inner() {
+                match child.as_rule() {
+                    Rule::bin => commands.push(recurse_commands(child, heredocs)?),
+                    Rule::separator => {
+                        let separator = child.into_inner().next().unwrap();
+                        if separator.as_rule() == Rule::background {
+                            let command = commands.pop().unwrap();
+                            commands.push(Command::Background {
+                                command: Box::new(command),
+                            });
+                        }
+                    }
+                    _ => unreachable!(),
+                }
+            }
+
+            let mut commands = commands.into_iter();
+            let mut left = commands.next().unwrap();
+            for right in commands {
+                left = Command::Sequence {
+                    left: Box::new(left),
+                    right: Box::new(right),
+                };
+            }
+
+            Ok(left)
+        }
         Rule::pipeline => {
             let mut steps = vec![];
-            let inner = pair.into_inner().into_iter().collect::<Vec<_>>();
-            for child in inner
-                .iter()
-                .filter(|p| p.as_rule() == Rule::invocation)
-                .cloned()
-            {
-                steps.push(recurse_commands(child)?);
+            for child in pair.into_inner() {
+                steps.push(recurse_commands(child, heredocs)?);
+            }
+            // A lone command isn't a pipeline, and running it as one would put
+            // blocks and function definitions in a subshell.
+            if steps.len() == 1 {
+                return Ok(steps.pop().unwrap());
             }
 
-            let redirect = inner
-                .iter()
-                .find(|p| p.as_rule() == Rule::redirect)
-                .map(|p| {
-                    p.clone()
-                        .into_inner()
+            Ok(Command::Pipeline { steps })
+        }
+        Rule::if_clause => {
+            let pairs = pair.into_inner().collect::<Vec<_>>();
+            let mut lists = get_rules(&pairs, Rule::list)
+                .into_iter()
+                .map(|list| recurse_commands(list, heredocs))
+                .collect::<Result<Vec<_>>>()?;
+            let otherwise = match get_rule(&pairs, Rule::kw_else) {
+                Ok(_) => Some(Box::new(lists.pop().unwrap())),
+                Err(_) => None,
+            };
+            let mut lists = lists.into_iter();
+            let mut branches = vec![];
+            while let (Some(condition), Some(body)) = (lists.next(), lists.next()) {
+                branches.push((condition, body));
+            }
+
+            Ok(Command::If {
+                branches,
+                otherwise,
+            })
+        }
+        Rule::while_clause => {
+            let pairs = pair.into_inner().collect::<Vec<_>>();
+            let until = pairs[0].as_rule() == Rule::kw_until;
+            let mut lists = get_rules(&pairs, Rule::list).into_iter();
+            let condition = recurse_commands(lists.next().unwrap(), heredocs)?;
+            let body = recurse_commands(lists.next().unwrap(), heredocs)?;
+
+            Ok(Command::While {
+                condition: Box::new(condition),
+                body: Box::new(body),
+                until,
+            })
+        }
+        Rule::for_clause => {
+            let pairs = pair.into_inner().collect::<Vec<_>>();
+            let name = get_rule(&pairs, Rule::assign_name)?.as_str().to_owned();
+            let words = get_rules(&pairs, Rule::arg)
+                .into_iter()
+                .map(|arg| recurse_args(arg, heredocs))
+                .collect::<Result<Vec<_>>>()?;
+            let body = recurse_commands(get_rule(&pairs, Rule::list)?, heredocs)?;
+
+            Ok(Command::For {
+                name,
+                words,
+                body: Box::new(body),
+            })
+        }
+        Rule::subshell | Rule::group => {
+            let is_subshell = pair.as_rule() == Rule::subshell;
+            let pairs = pair.into_inner().collect::<Vec<_>>();
+            let mut redirects = vec![];
+            for redirect in get_rules(&pairs, Rule::redirect) {
+                redirects.append(&mut recurse_redirect(redirect, heredocs)?);
+            }
+            // A group's commands are inside its braces.
+            let inner = if is_subshell {
+                pairs
+            } else {
+                get_rule(&pairs, Rule::brace_group)?.into_inner().collect()
+            };
+            let body = Box::new(recurse_commands(get_rule(&inner, Rule::list)?, heredocs)?);
+
+            Ok(if is_subshell {
+                Command::Subshell { body, redirects }
+            } else {
+                Command::Group { body, redirects }
+            })
+        }
+        Rule::function_def => {
+            let source = pair.as_str().to_owned();
+            let pairs = pair.into_inner().collect::<Vec<_>>();
+            let name = get_rule(&pairs, Rule::assign_name)?.as_str().to_owned();
+            let group = get_rule(&pairs, Rule::brace_group)?
+                .into_inner()
+                .collect::<Vec<_>>();
+            let body = recurse_commands(get_rule(&group, Rule::list)?, heredocs)?;
+
+            Ok(Command::Function {
+                function: Box::new(Function { name, body, source }),
+            })
+        }
+        Rule::case_clause => {
+            let pairs = pair.into_inner().collect::<Vec<_>>();
+            let word = recurse_args(get_rule(&pairs, Rule::arg)?, heredocs)?;
+            let items = get_rules(&pairs, Rule::case_item)
+                .into_iter()
+                .map(|item| {
+                    let pairs = item.into_inner().collect::<Vec<_>>();
+                    let patterns = get_rules(&pairs, Rule::case_pattern)
                         .into_iter()
-                        .next()
-                        .unwrap()
-                        .as_str()
-                        .to_owned()
-                });
+                        .map(|pattern| recurse_args(pattern.into_inner().next().unwrap(), heredocs))
+                        .collect::<Result<Vec<_>>>()?;
+                    let body = match get_rule(&pairs, Rule::list) {
+                        Ok(list) => Some(recurse_commands(list, heredocs)?),
+                        Err(_) => None,
+                    };
+                    Ok(CaseItem { patterns, body })
+                })
+                .collect::<Result<Vec<_>>>()?;
 
-            Ok(Command::Pipeline { steps, redirect })
+            Ok(Command::Case {
+                word: Box::new(word),
+                items,
+            })
         }
         _ => unreachable!(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failure(input: &str) -> anyhow::Error {
+        match parse_pest(input) {
+            Ok(_) => panic!("`{}` parsed", input),
+            Err(e) => e,
+        }
+    }
+
+    /// The error for input that's wrong however it goes on.
+    fn error(input: &str) -> ParseError {
+        let e = failure(input);
+        assert!(e.downcast_ref::<Incomplete>().is_none(), "`{}`", input);
+        e.downcast_ref::<ParseError>().unwrap().clone()
+    }
+
+    /// The error for input that another line could finish.
+    fn incomplete(input: &str) -> ParseError {
+        let e = failure(input);
+        e.downcast_ref::<Incomplete>().unwrap().error.clone()
+    }
+
+    fn simple(input: &str) -> (Vec<Assignment>, String, Vec<Arg>, Vec<Redirect>) {
+        match parse_pest(input).unwrap() {
+            Some(Command::Simple {
+                assignments,
+                command,
+                args,
+                redirects,
+            }) => (assignments, command, args, redirects),
+            other => panic!("`{}` parsed as {:?}", input, other),
+        }
+    }
+
+    /// Spells out how an argument was split up, with each piece of literal
+    /// text in single quotes, since it loses its own quotes in parsing.
+    fn shape(arg: &Arg) -> String {
+        match arg {
+            Arg::String { arg_string } => format!("'{}'", arg_string),
+            Arg::Compound { parts } => parts.iter().map(shape).collect(),
+            arg => arg.to_string(),
+        }
+    }
+
+    fn args(input: &str) -> Vec<String> {
+        simple(input).2.iter().map(shape).collect()
+    }
+
+    fn redirects(input: &str) -> Vec<(i32, RedirectMode, String)> {
+        let redirects = simple(input).3.into_iter();
+        redirects
+            .map(|r| (r.fd, r.mode, shape(&r.target)))
+            .collect()
+    }
+
+    #[test]
+    fn points_at_unexpected_text() {
+        assert_eq!(
+            error("echo )").to_string(),
+            "syntax error: unexpected `)`\n    echo )\n         ^"
+        );
+        assert_eq!(
+            error("echo a; fi").to_string(),
+            "syntax error: unexpected `fi`\n    echo a; fi\n            ^^"
+        );
+        assert_eq!(
+            error("ls && && ls").to_string(),
+            "syntax error: unexpected `&`, expected a command\n    ls && && ls\n          ^"
+        );
+    }
+
+    #[test]
+    fn asks_for_more_when_unfinished() {
+        let e = incomplete("if true; then echo");
+        assert_eq!(e.expected, ["`elif`", "`else`", "`fi`"]);
+        assert_eq!(
+            e.to_string(),
+            "syntax error: unexpected end of input, expected `elif`, `else` or `fi`\n    \
+             if true; then echo\n                  ^^^^"
+        );
+
+        let e = incomplete("echo a |");
+        assert_eq!(e.message, "unexpected end of input, expected a command");
+        assert_eq!(e.column, 8);
+
+        let e = incomplete("echo \"abc");
+        assert_eq!(e.message, "unterminated quote");
+        assert_eq!(e.excerpt(), "    echo \"abc\n         ^^^^");
+
+        assert_eq!(
+            incomplete("cat <<EOF").message,
+            "unexpected end of input, expected here-document delimiter `EOF`"
+        );
+        assert_eq!(
+            incomplete("echo a\\").message,
+            "unexpected end of input, expected the rest of the line"
+        );
+    }
+
+    #[test]
+    fn finds_the_error_either_way() {
+        for input in ["echo )", "echo a |"] {
+            assert!(parse_error(&failure(input)).is_some(), "`{}`", input);
+        }
+    }
+
+    #[test]
+    fn counts_lines_and_characters() {
+        let e = error("if true; then\n\tédit )\nfi");
+        assert_eq!((e.line, e.column), (2, 7));
+        assert_eq!(e.text, "\tédit )");
+        assert_eq!(e.excerpt(), "     édit )\n          ^");
+    }
+
+    #[test]
+    fn reads_redirects() {
+        use RedirectMode::*;
+
+        assert_eq!(
+            redirects("cmd < in > out >> log 2> errs 3< extra"),
+            [
+                (0, Read, "'in'".into()),
+                (1, Write, "'out'".into()),
+                (1, Append, "'log'".into()),
+                (2, Write, "'errs'".into()),
+                (3, Read, "'extra'".into()),
+            ]
+        );
+        assert_eq!(
+            redirects("cmd 2>&1 >&2 2>&-"),
+            [
+                (2, Duplicate, "'1'".into()),
+                (1, Duplicate, "'2'".into()),
+                (2, Duplicate, "'-'".into()),
+            ]
+        );
+        // `&>` is `>` followed by `2>&1`.
+        assert_eq!(
+            redirects("cmd &> all"),
+            [(1, Write, "'all'".into()), (2, Duplicate, "'1'".into())]
+        );
+        assert_eq!(
+            redirects("cmd <<< \"$x y\" >$out"),
+            [(0, HereString, "$x' y'".into()), (1, Write, "$out".into())]
+        );
+        // Redirects can come between arguments.
+        let input = "echo a 2>/dev/null b >out";
+        assert_eq!(args(input), ["'a'", "'b'"]);
+        assert_eq!(redirects(input).len(), 2);
+    }
+
+    #[test]
+    fn reads_heredocs() {
+        use RedirectMode::HereDoc;
+
+        assert_eq!(
+            redirects("cat <<EOF\na $x\n$(date)\nEOF"),
+            [(0, HereDoc, "'a '$x'\n'$(date)'\n'".into())]
+        );
+        // Quoting any of the delimiter leaves the body as it is.
+        for input in [
+            "cat <<'EOF'\na $x\nEOF",
+            "cat <<\"EOF\"\na $x\nEOF",
+            "cat <<E\\OF\na $x\nEOF",
+        ] {
+            assert_eq!(
+                redirects(input),
+                [(0, HereDoc, "'a $x\n'".into())],
+                "`{}`",
+                input
+            );
+        }
+        assert_eq!(
+            redirects("cat <<-EOF\n\ta\n\t\tb\n\tEOF"),
+            [(0, HereDoc, "'a\nb\n'".into())]
+        );
+        assert_eq!(
+            redirects("cat <<A 3<<B\n1\nA\n2\nB"),
+            [(0, HereDoc, "'1\n'".into()), (3, HereDoc, "'2\n'".into())]
+        );
+        // The command goes on after the operator, and the body starts on the
+        // next line.
+        let command = parse_pest("cat <<EOF | wc -l; echo b\nx\nEOF\n").unwrap();
+        assert_eq!(command.unwrap().to_string(), "cat <<EOF | wc -l; echo b");
+        assert_eq!(redirects("cat <<EOF\nEOF"), [(0, HereDoc, "".into())]);
+    }
+
+    #[test]
+    fn joins_quoted_and_unquoted_parts() {
+        assert_eq!(
+            args(r#"echo "$HOME/bin" pre$V --out=$(date) "built at $(date)""#),
+            [
+                "$HOME'/bin'",
+                "'pre'$V",
+                "'--out='$(date)",
+                "'built at '$(date)"
+            ]
+        );
+        assert_eq!(
+            args(r#"echo 'a b'c '$x' \$x "a\"b" "cost: $""#),
+            ["'a b''c'", "'$x'", "'$''x'", "'a''\"''b'", "'cost: ''$'"]
+        );
+        // Only unquoted words are left for globbing and tilde expansion.
+        assert_eq!(args("echo *.rs ~ '*.rs'"), ["*.rs", "~", "'*.rs'"]);
+        assert_eq!(args("echo ${x:-a b} \"${#x}\""), ["${x:-a b}", "${#x}"]);
+    }
+
+    #[test]
+    fn reads_assignments() {
+        fn assignments(input: &str) -> (Vec<(String, String)>, String) {
+            let (assignments, command, _, _) = simple(input);
+            let assignments = assignments
+                .iter()
+                .map(|a| (a.name.clone(), shape(&a.value)));
+            (assignments.collect(), command)
+        }
+        let pair = |name: &str, value: &str| (name.to_string(), value.to_string());
+
+        assert_eq!(
+            assignments("A=1 B=\"x y\" env a"),
+            (vec![pair("A", "'1'"), pair("B", "'x y'")], "env".into())
+        );
+        // Without a command, they're for the shell.
+        assert_eq!(
+            assignments("PATH=$HOME/bin:$PATH _x="),
+            (
+                vec![pair("PATH", "$HOME'/bin:'$PATH"), pair("_x", "''")],
+                "".into()
+            )
+        );
+        assert_eq!(assignments("X= cmd"), (vec![pair("X", "''")], "cmd".into()));
+        // Only leading words that start with a name are assignments.
+        assert_eq!(assignments("echo A=1"), (vec![], "echo".into()));
+        assert_eq!(assignments("1A=1"), (vec![], "1A=1".into()));
+        assert_eq!(args("A=1 echo B=2"), ["'B=2'"]);
+    }
+
+    #[test]
+    fn skips_comments() {
+        assert_eq!(args("echo a # b"), ["'a'"]);
+        // A comment runs to the end of the line, backslash and all.
+        assert_eq!(args("echo a # b \\"), ["'a'"]);
+        // Only a `#` that starts a word starts a comment.
+        assert_eq!(args("echo a#b '# c' \"#\""), ["'a#b'", "'# c'", "'#'"]);
+        assert!(parse_pest("# only").unwrap().is_none());
+        assert!(parse_pest("\n  # one\n# two\n").unwrap().is_none());
+        let command = parse_pest("if true; then # yes\n  echo a # b\nfi # c").unwrap();
+        assert_eq!(command.unwrap().to_string(), "if true; then echo a; fi");
+    }
+
+    #[test]
+    fn continues_lines_after_backslash() {
+        assert_eq!(args("echo a \\\n  b"), ["'a'", "'b'"]);
+        // Inside a word, it leaves nothing between the two halves.
+        assert_eq!(args("echo a\\\nb"), ["'a''''b'"]);
+        let command = parse_pest("echo a |\\\n  wc -l").unwrap();
+        assert_eq!(command.unwrap().to_string(), "echo a | wc -l");
+        // Quotes keep it as it is.
+        assert_eq!(args("echo 'a\\\nb'"), ["'a\\\nb'"]);
+    }
+
+    #[test]
+    fn reads_process_substitutions() {
+        assert_eq!(
+            args("diff <(sort a | uniq) >(cat) --in=<(ls)"),
+            ["<(sort a | uniq)", ">(cat)", "'--in='<(ls)"]
+        );
+        assert_eq!(
+            redirects("echo a > >(cat)"),
+            [(1, RedirectMode::Write, ">(cat)".into())]
+        );
+        // A `<` and `(` with a space between are a redirect and a subshell.
+        assert!(parse_pest("cat < (ls)").is_err());
+        assert_eq!(
+            incomplete("diff <(sort a").message,
+            "unexpected end of input, expected `)`"
+        );
+    }
+}
@@ -0,0 +1,270 @@
+use std::{
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    os::unix::{
+        fs::FileTypeExt,
+        io::{FromRawFd, RawFd},
+    },
+    process::Stdio,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use anyhow::{bail, Result};
+use nix::{
+    fcntl::{fcntl, FcntlArg, OFlag},
+    unistd::pipe2,
+};
+
+use crate::{
+    command::{eval_arg, Arg},
+    state::State,
+};
+
+/// One end of a child's standard stream, before it's handed to `std::process`.
+///
+/// Unlike `Stdio`, this can be duplicated, which is what `2>&1` needs.
+#[derive(Debug)]
+pub enum Io {
+    Inherit,
+    Null,
+    File(File),
+}
+
+/// Returns `(read, write)` ends of a new pipe. Both are close-on-exec, so
+/// only the child they're handed to keeps them open.
+pub fn pipe() -> Result<(File, File)> {
+    let (read, write) = pipe2(OFlag::O_CLOEXEC)?;
+    let read = unsafe { File::from_raw_fd(read) };
+    let write = unsafe { File::from_raw_fd(write) };
+
+    Ok((read, write))
+}
+
+impl Io {
+    /// Returns the read end of a pipe that yields `text` and then EOF.
+    pub fn from_string(text: String) -> Result<Io> {
+        let (read, mut write) = pipe()?;
+        thread::spawn(move || {
+            let _ = write.write_all(text.as_bytes());
+        });
+
+        Ok(Io::File(read))
+    }
+
+    /// Duplicates this stream. `fd` is the descriptor it currently occupies,
+    /// which is what an inherited stream refers to.
+    pub fn duplicate(&self, fd: RawFd) -> Result<Io> {
+        Ok(match self {
+            Io::Inherit => {
+                let new_fd = fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(3))?;
+                Io::File(unsafe { File::from_raw_fd(new_fd) })
+            }
+            Io::Null => Io::Null,
+            Io::File(file) => Io::File(file.try_clone()?),
+        })
+    }
+}
+
+impl Io {
+    pub fn try_clone(&self) -> Result<Io> {
+        Ok(match self {
+            Io::Inherit => Io::Inherit,
+            Io::Null => Io::Null,
+            Io::File(file) => Io::File(file.try_clone()?),
+        })
+    }
+}
+
+impl From<Io> for Stdio {
+    fn from(io: Io) -> Self {
+        match io {
+            Io::Inherit => Stdio::inherit(),
+            Io::Null => Stdio::null(),
+            Io::File(file) => Stdio::from(file),
+        }
+    }
+}
+
+/// A stream a builtin writes to in place of its own stdout or stderr.
+///
+/// Output bound for a pipe is held back until the builtin is done and then
+/// written from a thread of its own, since whatever reads the pipe may be a
+/// later pipeline stage that hasn't been started yet.
+pub struct Output {
+    io: Io,
+    fd: RawFd,
+    buffer: Option<Vec<u8>>,
+}
+
+impl Output {
+    /// `fd` is the descriptor the builtin sees `io` as, which is what an
+    /// inherited stream refers to.
+    pub fn new(io: Io, fd: RawFd) -> Result<Self> {
+        let buffer = match &io {
+            Io::File(file) if file.metadata()?.file_type().is_fifo() => Some(vec![]),
+            _ => None,
+        };
+
+        Ok(Self { io, fd, buffer })
+    }
+
+    /// A copy of the stream, for commands run on the builtin's behalf. They
+    /// write to it directly rather than through what's held back.
+    pub fn try_clone_io(&self) -> Result<Io> {
+        self.io.try_clone()
+    }
+
+    /// Hands anything held back over to a writer thread.
+    pub fn finish(self) {
+        if let (Io::File(mut file), Some(buffer)) = (self.io, self.buffer) {
+            if !buffer.is_empty() {
+                thread::spawn(move || {
+                    let _ = file.write_all(&buffer);
+                });
+            }
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(buffer) = &mut self.buffer {
+            buffer.extend_from_slice(buf);
+            return Ok(buf.len());
+        }
+
+        match &mut self.io {
+            Io::Inherit if self.fd == 2 => io::stderr().write(buf),
+            Io::Inherit => io::stdout().write(buf),
+            Io::Null => Ok(buf.len()),
+            Io::File(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.io {
+            Io::Inherit if self.fd == 2 => io::stderr().flush(),
+            Io::Inherit => io::stdout().flush(),
+            Io::File(file) if self.buffer.is_none() => file.flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The three standard streams of a single invocation.
+#[derive(Debug)]
+pub struct Stdios {
+    pub stdin: Io,
+    pub stdout: Io,
+    pub stderr: Io,
+}
+
+impl Stdios {
+    pub fn new(stdin: Io, stdout: Io) -> Self {
+        Self {
+            stdin,
+            stdout,
+            stderr: Io::Inherit,
+        }
+    }
+
+    fn get(&self, fd: RawFd) -> Result<&Io> {
+        Ok(match fd {
+            0 => &self.stdin,
+            1 => &self.stdout,
+            2 => &self.stderr,
+            _ => bail!("Unsupported file descriptor {}", fd),
+        })
+    }
+
+    fn set(&mut self, fd: RawFd, io: Io) -> Result<()> {
+        match fd {
+            0 => self.stdin = io,
+            1 => self.stdout = io,
+            2 => self.stderr = io,
+            _ => bail!("Unsupported file descriptor {}", fd),
+        }
+
+        Ok(())
+    }
+
+    /// Applies `redirects` left to right, so `> out 2>&1` and `2>&1 > out`
+    /// behave as they do in other shells.
+    pub async fn apply(&mut self, redirects: &[Redirect], state: &Arc<Mutex<State>>) -> Result<()> {
+        for redirect in redirects {
+            let target = eval_arg(&redirect.target, state).await?;
+            let io = match redirect.mode {
+                RedirectMode::Read => Io::File(File::open(target)?),
+                RedirectMode::HereString => Io::from_string(format!("{}\n", target))?,
+                RedirectMode::HereDoc => Io::from_string(target)?,
+                RedirectMode::Write => Io::File(File::create(target)?),
+                RedirectMode::Append => {
+                    Io::File(OpenOptions::new().create(true).append(true).open(target)?)
+                }
+                RedirectMode::Duplicate => {
+                    if target == "-" {
+                        Io::Null
+                    } else {
+                        let from: RawFd = target
+                            .parse()
+                            .map_err(|_| anyhow::anyhow!("{}: bad file descriptor", target))?;
+                        self.get(from)?.duplicate(from)?
+                    }
+                }
+            };
+            self.set(redirect.fd, io)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedirectMode {
+    /// `<`
+    Read,
+    /// `<<<`
+    HereString,
+    /// `<<` and `<<-`, where the target is the document body
+    HereDoc,
+    /// `>`
+    Write,
+    /// `>>`
+    Append,
+    /// `>&`, where the target is another descriptor
+    Duplicate,
+}
+
+impl RedirectMode {
+    pub fn default_fd(&self) -> RawFd {
+        match self {
+            RedirectMode::Read | RedirectMode::HereString | RedirectMode::HereDoc => 0,
+            RedirectMode::Write | RedirectMode::Append | RedirectMode::Duplicate => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Redirect {
+    pub fd: RawFd,
+    pub mode: RedirectMode,
+    pub target: Arg,
+}
+
+impl Display for Redirect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.fd != self.mode.default_fd() {
+            self.fd.fmt(f)?;
+        }
+        match self.mode {
+            RedirectMode::Read => write!(f, "< {}", self.target),
+            RedirectMode::HereString => write!(f, "<<< {}", self.target),
+            RedirectMode::HereDoc => write!(f, "<<EOF"),
+            RedirectMode::Write => write!(f, "> {}", self.target),
+            RedirectMode::Append => write!(f, ">> {}", self.target),
+            RedirectMode::Duplicate => write!(f, ">&{}", self.target),
+        }
+    }
+}
@@ -5,13 +5,13 @@ use protos::{
     create_sock_path,
     env_server::{Env, EnvServer},
     status_server::{Status as StatusTrait, StatusServer},
-    EnvVar, GetEnvResponse, GetStatusResponse,
+    EnvVar, GetEnvRequest, GetEnvResponse, GetStatusResponse, SetEnvResponse,
 };
 use tokio::net::UnixListener;
 use tokio_stream::wrappers::UnixListenerStream;
 use tonic::{transport::Server, Request, Response, Status};
 
-use crate::state::State;
+use crate::{state::State, vars::check_name};
 
 struct StatusListener {
     state: Arc<Mutex<State>>,
@@ -35,23 +35,50 @@ impl StatusTrait for StatusListener {
     }
 }
 
-struct EnvListener;
+struct EnvListener {
+    state: Arc<Mutex<State>>,
+}
 
 #[tonic::async_trait]
 impl Env for EnvListener {
-    async fn set_env(&self, req: Request<EnvVar>) -> Result<Response<()>, Status> {
+    /// Queues the variable rather than setting it straight away, since the
+    /// shell may be in the middle of a command.
+    async fn set_env(&self, req: Request<EnvVar>) -> Result<Response<SetEnvResponse>, Status> {
         let req = req.into_inner();
-        std::env::set_var(req.key, req.value);
+        let mut state = self.state.lock().unwrap();
+        let checked = check_name(&req.key).and_then(|_| state.vars.check_writable(&req.key));
 
-        Ok(Response::new(()))
+        Ok(Response::new(match checked {
+            Ok(()) => {
+                state.pending_vars.push((req.key, req.value));
+                SetEnvResponse {
+                    accepted: true,
+                    reason: String::new(),
+                }
+            }
+            Err(e) => SetEnvResponse {
+                accepted: false,
+                reason: e.to_string(),
+            },
+        }))
     }
 
-    async fn get_env(&self, _req: Request<()>) -> Result<Response<GetEnvResponse>, Status> {
-        let mut vars = vec![];
+    async fn get_env(
+        &self,
+        req: Request<GetEnvRequest>,
+    ) -> Result<Response<GetEnvResponse>, Status> {
+        let include_locals = req.into_inner().include_locals;
+        let state = self.state.lock().unwrap();
 
-        for (key, value) in std::env::vars() {
-            vars.push(EnvVar { key, value });
-        }
+        let vars = state
+            .vars
+            .iter()
+            .filter(|(_, var)| include_locals || var.exported)
+            .map(|(key, var)| EnvVar {
+                key: key.clone(),
+                value: var.value.clone(),
+            })
+            .collect();
 
         Ok(Response::new(GetEnvResponse { vars }))
     }
@@ -59,8 +86,13 @@ impl Env for EnvListener {
 
 pub async fn start_services(state: Arc<Mutex<State>>) -> Result<()> {
     let sock_path = create_sock_path()?;
-    std::env::set_var("PSH_SERVICE_SOCK", sock_path.to_string_lossy().to_string());
-    let env_listener = EnvListener;
+    state.lock().unwrap().vars.export(
+        "PSH_SERVICE_SOCK",
+        Some(sock_path.to_string_lossy().to_string()),
+    )?;
+    let env_listener = EnvListener {
+        state: state.clone(),
+    };
     let status_listener = StatusListener { state };
 
     let uds = UnixListener::bind(sock_path)?;
@@ -1,23 +1,32 @@
 use std::{
     collections::HashMap,
-    env,
-    fs::File,
-    io::Read,
+    env, fs,
     path::PathBuf,
-    process::Stdio,
     sync::{Arc, Mutex},
 };
 
 use anyhow::Result;
 use directories::{ProjectDirs, UserDirs};
+use nix::unistd::{getpgrp, isatty, setpgid, Pid};
 use owo_colors::OwoColorize;
 use rustyline::{
-    completion::FilenameCompleter, highlight::Highlighter, hint::HistoryHinter, CompletionType,
-    Editor,
+    completion::{Completer, FilenameCompleter, Pair},
+    highlight::Highlighter,
+    hint::HistoryHinter,
+    validate::{ValidationContext, ValidationResult, Validator},
+    CompletionType, Context, Editor,
 };
 use rustyline_derive::{Completer, Helper, Highlighter, Hinter, Validator};
 
-use crate::{parser::parse_pest, state::State};
+use crate::{
+    builtins::Builtins,
+    command::{LoopJump, Return},
+    job::give_terminal,
+    parser::{parse_error, parse_pest, Incomplete, ParseError},
+    redirect::Io,
+    state::{ShellOptions, State},
+    vars::Variables,
+};
 
 fn path_prompt() -> Result<String> {
     let ud = UserDirs::new().ok_or_else(|| anyhow::anyhow!("Couldn't find user dirs"))?;
@@ -38,64 +47,276 @@ fn path_prompt() -> Result<String> {
     }
 }
 
-async fn load_state() -> Result<Arc<Mutex<State>>> {
+/// Sets up the shell's state. Only an `interactive` shell does job control,
+/// taking over the terminal to hand it to each foreground job.
+pub async fn load_state(interactive: bool) -> Result<Arc<Mutex<State>>> {
     let ud = UserDirs::new().expect("user dirs");
     let mut history_path = PathBuf::from(ud.home_dir());
     history_path.push(".psh_history");
+    if interactive {
+        // Jobs get their own process groups, so psh needs one too in order to
+        // take the terminal back from them.
+        let _ = setpgid(Pid::from_raw(0), Pid::from_raw(0));
+        give_terminal(getpgrp())?;
+    }
     let state = Arc::new(Mutex::new(State {
         aliases: HashMap::new(),
+        builtins: Builtins::new(),
+        vars: Variables::from_env(),
         history_path,
         current_command: None,
-        running_pid: None,
+        foreground_pgid: None,
+        jobs: Vec::new(),
+        interactive,
+        shell_pgid: getpgrp(),
+        options: ShellOptions::default(),
+        last_status: 0,
+        pipestatus: Vec::new(),
+        pending_vars: Vec::new(),
+        functions: HashMap::new(),
+        positional: Vec::new(),
+        function_depth: 0,
+        returning: None,
+        loop_depth: 0,
+        jumping: None,
+        shell_name: env::args().next().unwrap_or_else(|| String::from("psh")),
+        substitutions: Vec::new(),
+        substitution_pids: Vec::new(),
+        signals: None,
     }));
 
-    run_rc(&state).await?;
-
     Ok(state)
 }
 
+/// The rc files to run at startup, in order: the system-wide one, then the
+/// user's, which `$PSHRC` can point somewhere else.
+fn rc_paths() -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from("/etc/psh/pshrc")];
+    match env::var_os("PSHRC") {
+        Some(rc) => paths.push(PathBuf::from(rc)),
+        None => {
+            if let Some(pd) = ProjectDirs::from("net", "paulsanford", "psh") {
+                paths.push(pd.config_dir().join("pshrc"));
+            }
+        }
+    }
+
+    paths
+}
+
 async fn run_rc(state: &Arc<Mutex<State>>) -> Result<()> {
-    let pd = ProjectDirs::from("net", "paulsanford", "psh").expect("project dirs");
-    let mut rc = PathBuf::from(pd.config_dir());
-    rc.push("pshrc");
+    for rc in rc_paths() {
+        if !rc.exists() {
+            continue;
+        }
+        let script = match fs::read_to_string(&rc) {
+            Ok(script) => script,
+            Err(e) => {
+                eprintln!("psh: {}: {}", rc.display(), e);
+                continue;
+            }
+        };
 
-    if !rc.exists() {
-        return Ok(());
+        run_script(
+            &script,
+            &rc.to_string_lossy(),
+            Io::Inherit,
+            Io::Inherit,
+            state,
+        )
+        .await?;
     }
 
-    let mut file = File::open(rc)?;
-    let mut cont = String::new();
-    file.read_to_string(&mut cont)?;
+    Ok(())
+}
 
-    for (num, line) in cont.lines().enumerate() {
-        match parse_pest(line) {
-            Ok(parsed) => {
-                if let Err(e) = parsed.run(Stdio::null(), Stdio::inherit(), &state).await {
-                    eprintln!("Error on line {} of rc file: {}", num, e);
+/// Runs `script` a command at a time, reading on when a command spans
+/// several lines. `source` names the script in error messages.
+///
+/// Returns the exit code of the last command: 2 if it couldn't be parsed and
+/// 1 if it couldn't be run.
+pub async fn run_script(
+    script: &str,
+    source: &str,
+    stdin: Io,
+    stdout: Io,
+    state: &Arc<Mutex<State>>,
+) -> Result<i32> {
+    let mut status = 0;
+    let mut pending = String::new();
+    let mut start = 0;
+    for (num, line) in script.lines().enumerate() {
+        if num == 0 && line.starts_with("#!") {
+            continue;
+        }
+        if pending.is_empty() {
+            start = num;
+        } else {
+            pending.push('\n');
+        }
+        pending.push_str(line);
+
+        match parse_pest(&pending) {
+            Ok(None) => {}
+            Ok(Some(parsed)) => {
+                let result = parsed
+                    .run(stdin.try_clone()?, stdout.try_clone()?, state)
+                    .await;
+                match result {
+                    Ok(result) => status = result.status,
+                    Err(e) => match e.downcast::<Return>() {
+                        // `return` outside of a function ends a sourced script.
+                        Ok(Return { status }) => return Ok(status),
+                        // `break` and `continue` are for a loop around `source`.
+                        Err(e) if e.is::<LoopJump>() => return Err(e),
+                        Err(e) => {
+                            eprintln!("psh: {}:{}: {}", source, start + 1, e);
+                            status = 1;
+                        }
+                    },
                 }
             }
+            Err(e) if e.is::<Incomplete>() => continue,
             Err(e) => {
-                eprintln!("Error on line {} of rc file: {}", num, e);
+                report_parse_error(&e, source, start);
+                status = 2;
             }
         }
+        state.lock().unwrap().current_command = None;
+        pending.clear();
     }
 
-    Ok(())
+    if !pending.is_empty() {
+        if let Err(e) = parse_pest(&pending) {
+            report_parse_error(&e, source, start);
+            status = 2;
+        }
+    }
+
+    Ok(status)
+}
+
+/// Reports a failure to parse the command starting on line `start`, counting
+/// from 0, of a script.
+fn report_parse_error(e: &anyhow::Error, source: &str, start: usize) {
+    match parse_error(e) {
+        Some(e) => eprintln!(
+            "psh: {}:{}:{}: syntax error: {}\n{}",
+            source,
+            start + e.line,
+            e.column,
+            e.message,
+            e.excerpt()
+        ),
+        None => eprintln!("psh: {}:{}: {}", source, start + 1, e),
+    }
 }
 
 #[derive(Completer, Helper, Validator, Highlighter, Hinter)]
 struct PshHelper {
     #[rustyline(Completer)]
-    completer: FilenameCompleter,
+    completer: PshCompleter,
     #[rustyline(Hinter)]
     hinter: HistoryHinter,
     #[rustyline(Highlighter)]
     highlighter: PshHighlighter,
+    #[rustyline(Validator)]
+    validator: PshValidator,
+}
+
+/// Completes command names in command position, and file names everywhere else.
+struct PshCompleter {
+    filenames: FilenameCompleter,
+    state: Arc<Mutex<State>>,
+}
+
+impl PshCompleter {
+    /// Builtins, aliases and functions starting with `prefix`.
+    fn commands(&self, prefix: &str) -> Vec<Pair> {
+        let state = self.state.lock().unwrap();
+        let builtins = state.builtins.iter().map(|builtin| builtin.name());
+        let aliases = state.aliases.keys().map(String::as_str);
+        let functions = state.functions.keys().map(String::as_str);
+        let mut names = builtins
+            .chain(aliases)
+            .chain(functions)
+            .filter(|name| name.starts_with(prefix))
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        names.dedup();
+
+        names
+            .into_iter()
+            .map(|name| Pair {
+                display: name.to_owned(),
+                replacement: format!("{} ", name),
+            })
+            .collect()
+    }
 }
 
+impl Completer for PshCompleter {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let start = line[..pos]
+            .rfind(|c: char| c.is_whitespace() || "|&;(".contains(c))
+            .map(|idx| idx + 1)
+            .unwrap_or(0);
+        let word = &line[start..pos];
+        let before = line[..start].trim_end();
+        let command_position = before.is_empty() || before.ends_with(['|', '&', ';', '(']);
+
+        if command_position && !word.contains('/') {
+            let commands = self.commands(word);
+            if !commands.is_empty() {
+                return Ok((start, commands));
+            }
+        }
+
+        self.filenames.complete(line, pos, ctx)
+    }
+}
+
+struct PshValidator;
+
+impl Validator for PshValidator {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        Ok(match parse_pest(ctx.input()) {
+            Err(e) if e.is::<Incomplete>() => ValidationResult::Incomplete,
+            _ => ValidationResult::Valid(None),
+        })
+    }
+}
+
+/// Underlines syntax errors as they're typed. Input that's merely unfinished
+/// is left alone.
 struct PshHighlighter;
 
 impl Highlighter for PshHighlighter {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> std::borrow::Cow<'l, str> {
+        let Err(e) = parse_pest(line) else {
+            return std::borrow::Cow::Borrowed(line);
+        };
+        match e.downcast_ref::<ParseError>() {
+            Some(ParseError { span, .. }) if !span.is_empty() => {
+                let (before, rest) = line.split_at(span.start);
+                let (error, after) = rest.split_at(span.len());
+                std::borrow::Cow::Owned(format!("{}{}{}", before, error.red().underline(), after))
+            }
+            _ => std::borrow::Cow::Borrowed(line),
+        }
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize) -> bool {
+        true
+    }
+
     fn highlight_hint<'h>(&self, hint: &'h str) -> std::borrow::Cow<'h, str> {
         std::borrow::Cow::Owned(format!("{}", hint.truecolor(75, 75, 75)))
     }
@@ -111,8 +332,12 @@ impl Pshell {
         Arc::clone(&self.state)
     }
 
-    pub async fn new() -> Result<Self> {
-        let state = load_state().await?;
+    /// Starts an interactive shell, after running the rc files if `rc` is set.
+    pub async fn new(rc: bool) -> Result<Self> {
+        let state = load_state(isatty(0).unwrap_or(false)).await?;
+        if rc {
+            run_rc(&state).await?;
+        }
         let config = rustyline::Config::builder()
             .max_history_size(100)
             .auto_add_history(true)
@@ -121,9 +346,13 @@ impl Pshell {
             .completion_type(CompletionType::List)
             .build();
         let h = PshHelper {
-            completer: FilenameCompleter::new(),
+            completer: PshCompleter {
+                filenames: FilenameCompleter::new(),
+                state: Arc::clone(&state),
+            },
             hinter: HistoryHinter {},
             highlighter: PshHighlighter,
+            validator: PshValidator,
         };
         let mut editor = Editor::<PshHelper>::with_config(config)?;
         editor.set_helper(Some(h));
@@ -136,6 +365,14 @@ impl Pshell {
         let mut prompt_extra = String::from("");
 
         loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                state.apply_pending_vars();
+                for job in state.update_jobs() {
+                    eprintln!("{}", job);
+                }
+            }
+
             let pwd = path_prompt()?;
             let result = self
                 .editor
@@ -154,24 +391,17 @@ impl Pshell {
 
             let command_line = parse_pest(&input_line);
             match command_line {
-                Ok(command) => {
-                    let output = command
-                        .run(Stdio::inherit(), Stdio::inherit(), &self.state)
-                        .await;
+                Ok(None) => {}
+                Ok(Some(command)) => {
+                    let output = command.run(Io::Inherit, Io::Inherit, &self.state).await;
 
                     match output {
                         Ok(output) => {
-                            if let Some(mut output) = output.output {
-                                let exit = output.wait()?;
-                                self.state.lock().unwrap().current_command = None;
-                                self.state.lock().unwrap().running_pid = None;
-                                if exit.success() {
-                                    prompt_extra = String::from("");
-                                } else {
-                                    if let Some(code) = exit.code() {
-                                        prompt_extra = format!("{}>", code.white().on_red());
-                                    }
-                                }
+                            self.state.lock().unwrap().current_command = None;
+                            if output.success() {
+                                prompt_extra = String::from("");
+                            } else {
+                                prompt_extra = format!("{}>", output.status.white().on_red());
                             }
                         }
                         Err(e) => {
@@ -180,7 +410,7 @@ impl Pshell {
                     }
                 }
                 Err(e) => {
-                    eprintln!("{}", e);
+                    eprintln!("psh: {}", e);
                 }
             }
         }
@@ -1,6 +1,17 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{collections::HashMap, fs::File, path::PathBuf};
 
-use crate::command::Arg;
+use nix::{
+    errno::Errno,
+    sys::wait::{waitpid, WaitPidFlag, WaitStatus},
+    unistd::Pid,
+};
+
+use crate::{
+    builtins::Builtins,
+    command::{Arg, Command, LoopJump},
+    job::{Job, SignalCounts},
+    vars::Variables,
+};
 
 #[derive(Debug, Clone)]
 pub struct Alias {
@@ -24,10 +35,216 @@ impl Alias {
     }
 }
 
+/// A shell function, defined with `name() { list; }`.
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name: String,
+    pub body: Command,
+    /// The definition as it was written, which `functions` shows.
+    pub source: String,
+}
+
+/// A `<(list)` or `>(list)` in use: the shell's end of its pipe, and the
+/// subshell on the other end.
+#[derive(Debug)]
+pub struct ProcessSubstitution {
+    pub pipe: File,
+    pub pid: Pid,
+}
+
+/// What a glob pattern that matches nothing expands to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NoMatch {
+    /// The pattern itself, like other shells do by default.
+    #[default]
+    PassThrough,
+    /// Nothing at all (`nullglob`).
+    Empty,
+    /// The command isn't run (`failglob`).
+    Error,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ShellOptions {
+    /// A pipeline fails if any of its stages does, not only the last.
+    pub pipefail: bool,
+    pub nomatch: NoMatch,
+}
+
 #[derive(Debug)]
 pub struct State {
     pub aliases: HashMap<String, Alias>,
+    pub builtins: Builtins,
+    pub vars: Variables,
     pub history_path: PathBuf,
     pub current_command: Option<String>,
-    pub running_pid: Option<u32>,
+    pub foreground_pgid: Option<Pid>,
+    pub jobs: Vec<Job>,
+    /// Whether psh does job control: it owns a terminal, hands it to
+    /// foreground jobs and puts each job in a process group of its own.
+    pub interactive: bool,
+    pub shell_pgid: Pid,
+    pub options: ShellOptions,
+    /// `$?`
+    pub last_status: i32,
+    /// `$PIPESTATUS`: the exit code of each stage of the last pipeline.
+    pub pipestatus: Vec<i32>,
+    /// Variables other programs asked to export through the `Env` service,
+    /// waiting for the shell to get between commands.
+    pub pending_vars: Vec<(String, String)>,
+    /// Shell functions, by name.
+    pub functions: HashMap<String, Function>,
+    /// `$1`, `$2` and so on: the current function's arguments.
+    pub positional: Vec<String>,
+    /// How many function calls and sourced scripts deep the shell is.
+    pub function_depth: usize,
+    /// Set by `return` to the status the current function should end with.
+    pub returning: Option<i32>,
+    /// How many loops deep the shell is, for `break` and `continue`.
+    pub loop_depth: usize,
+    /// Set by `break` and `continue` to the loops they leave.
+    pub jumping: Option<LoopJump>,
+    /// `$0`: the script being run, or how psh itself was started.
+    pub shell_name: String,
+    /// Process substitutions whose command hasn't finished, oldest first.
+    pub substitutions: Vec<ProcessSubstitution>,
+    /// Subshells of process substitutions that are done with but may not
+    /// have exited yet.
+    pub substitution_pids: Vec<Pid>,
+    /// Set once something's listening for signals, which subshells don't.
+    pub signals: Option<SignalCounts>,
+}
+
+impl State {
+    /// The state a forked subshell starts with: everything the user defined,
+    /// but none of the parent's jobs or its terminal.
+    pub fn subshell(&self) -> State {
+        State {
+            aliases: self.aliases.clone(),
+            builtins: self.builtins.clone(),
+            vars: self.vars.clone(),
+            history_path: self.history_path.clone(),
+            current_command: self.current_command.clone(),
+            foreground_pgid: None,
+            jobs: Vec::new(),
+            interactive: false,
+            shell_pgid: self.shell_pgid,
+            options: self.options.clone(),
+            last_status: self.last_status,
+            pipestatus: self.pipestatus.clone(),
+            pending_vars: Vec::new(),
+            functions: self.functions.clone(),
+            positional: self.positional.clone(),
+            function_depth: self.function_depth,
+            returning: None,
+            loop_depth: self.loop_depth,
+            jumping: None,
+            shell_name: self.shell_name.clone(),
+            substitutions: Vec::new(),
+            substitution_pids: Vec::new(),
+            signals: None,
+        }
+    }
+
+    /// Adds a job to the job table and returns its job number. Jobs that
+    /// already have a number (e.g. stopped again after `fg`) keep it.
+    pub fn add_job(&mut self, mut job: Job) -> usize {
+        if job.id == 0 {
+            job.id = self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+        }
+        let id = job.id;
+        self.jobs.push(job);
+        self.jobs.sort_by_key(|job| job.id);
+
+        id
+    }
+
+    /// Removes a job from the table. With no id, takes the most recent one.
+    pub fn take_job(&mut self, id: Option<usize>) -> Option<Job> {
+        let index = match id {
+            Some(id) => self.jobs.iter().position(|job| job.id == id)?,
+            None => self.jobs.len().checked_sub(1)?,
+        };
+
+        Some(self.jobs.remove(index))
+    }
+
+    /// Catches up on background jobs and process substitutions after a
+    /// child changed state, leaving any news for `update_jobs` to report.
+    pub fn check_children(&mut self) {
+        for job in self.jobs.iter_mut() {
+            let _ = job.update();
+        }
+        self.reap_substitutions();
+    }
+
+    /// Checks on every job, returning the ones whose state changed since the
+    /// user was last told. Finished jobs are removed from the table.
+    pub fn update_jobs(&mut self) -> Vec<String> {
+        let mut changed = vec![];
+        for job in self.jobs.iter_mut() {
+            if let Err(e) = job.update() {
+                eprintln!("Couldn't check on job {}: {}", job.id, e);
+            }
+            if !job.notified {
+                job.notified = true;
+                changed.push(job.to_string());
+            }
+        }
+        self.jobs.retain(|job| !job.is_completed());
+
+        changed
+    }
+
+    /// Closes the shell's end of every process substitution after the first
+    /// `keep`, once the command using them is done, and reaps any of their
+    /// subshells that have exited. The rest are left to finish in their own
+    /// time, as other shells do.
+    pub fn end_substitutions(&mut self, keep: usize) {
+        let keep = keep.min(self.substitutions.len());
+        let ended = self.substitutions.drain(keep..).map(|s| s.pid);
+        self.substitution_pids.extend(ended);
+        self.reap_substitutions();
+    }
+
+    /// Reaps the subshells of finished process substitutions that have
+    /// exited.
+    pub fn reap_substitutions(&mut self) {
+        self.substitution_pids.retain(|pid| loop {
+            match waitpid(*pid, Some(WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::StillAlive) => break true,
+                Err(Errno::EINTR) => continue,
+                _ => break false,
+            }
+        });
+    }
+
+    /// Exports the variables queued by the `Env` service. Called between
+    /// commands, so nothing sees them change part way through one.
+    pub fn apply_pending_vars(&mut self) {
+        for (name, value) in std::mem::take(&mut self.pending_vars) {
+            if let Err(e) = self.vars.export(&name, Some(value)) {
+                eprintln!("psh: setenv: {}", e);
+            }
+        }
+    }
+
+    /// Records the outcome of a pipeline for `$?` and `$PIPESTATUS`, and
+    /// returns its overall exit code.
+    pub fn record_statuses(&mut self, statuses: Vec<i32>) -> i32 {
+        let status = if self.options.pipefail {
+            statuses
+                .iter()
+                .rev()
+                .find(|s| **s != 0)
+                .copied()
+                .unwrap_or(0)
+        } else {
+            statuses.last().copied().unwrap_or(0)
+        };
+        self.last_status = status;
+        self.pipestatus = statuses;
+
+        status
+    }
 }
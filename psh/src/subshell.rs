@@ -0,0 +1,207 @@
+use std::{
+    fs,
+    io::Write,
+    os::unix::io::{AsRawFd, RawFd},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use anyhow::Result;
+use nix::{
+    fcntl::{fcntl, open, FcntlArg, FdFlag, OFlag},
+    libc,
+    sys::{
+        signal::{signal, SigHandler, Signal},
+        stat::Mode,
+    },
+    unistd::{close, dup2, fork, setpgid, ForkResult, Pid},
+};
+
+use crate::{
+    command::{Command, LoopJump, Return},
+    redirect::{pipe, Io, Stdios},
+    state::{ProcessSubstitution, State},
+};
+
+/// Signals psh handles itself, which a subshell should treat like any other
+/// child process would.
+const SHELL_SIGNALS: [Signal; 5] = [
+    Signal::SIGCHLD,
+    Signal::SIGINT,
+    Signal::SIGTSTP,
+    Signal::SIGTTIN,
+    Signal::SIGTTOU,
+];
+
+/// Runs `command` in a forked copy of the shell and returns the child's pid.
+///
+/// If the shell does job control, the child joins process group `pgid`, or
+/// leads a new one if that's `None`. It exits with the command's status once
+/// it's done.
+pub fn fork_subshell(
+    command: &Command,
+    stdios: Stdios,
+    pgid: Option<Pid>,
+    state: &Arc<Mutex<State>>,
+) -> Result<Pid> {
+    fork_with(command, stdios, pgid, &[], state)
+}
+
+/// Starts `command` in a subshell on one end of a pipe, for `<(list)` or, if
+/// `writable`, `>(list)`, and returns the path of the shell's end. That stays
+/// open until the command it was expanded for is done.
+pub fn substitute_process(
+    command: &Command,
+    writable: bool,
+    state: &Arc<Mutex<State>>,
+) -> Result<String> {
+    let (read, write) = pipe()?;
+    let (pipe, stdios) = if writable {
+        (write, Stdios::new(Io::File(read), Io::Inherit))
+    } else {
+        (read, Stdios::new(Io::Null, Io::File(write)))
+    };
+    // Programs the shell runs need to inherit it to open the path.
+    let fd = pipe.as_raw_fd();
+    fcntl(fd, FcntlArg::F_SETFD(FdFlag::empty()))?;
+
+    let mut shell_ends = state
+        .lock()
+        .unwrap()
+        .substitutions
+        .iter()
+        .map(|substitution| substitution.pipe.as_raw_fd())
+        .collect::<Vec<_>>();
+    shell_ends.push(fd);
+    let pid = fork_with(command, stdios, None, &shell_ends, state)?;
+    state
+        .lock()
+        .unwrap()
+        .substitutions
+        .push(ProcessSubstitution { pipe, pid });
+
+    Ok(format!("/dev/fd/{}", fd))
+}
+
+/// [`fork_subshell`], which for a process substitution also takes the
+/// shell's ends of every substitution's pipe, for the child to close.
+fn fork_with(
+    command: &Command,
+    stdios: Stdios,
+    pgid: Option<Pid>,
+    shell_ends: &[RawFd],
+    state: &Arc<Mutex<State>>,
+) -> Result<Pid> {
+    let (subshell_state, interactive) = {
+        let state = state.lock().unwrap();
+        (state.subshell(), state.interactive)
+    };
+
+    match unsafe { fork() }? {
+        ForkResult::Parent { child } => {
+            // Set the group from both sides so neither can act on it before
+            // it exists.
+            if interactive {
+                let _ = setpgid(child, pgid.unwrap_or(child));
+            }
+            Ok(child)
+        }
+        ForkResult::Child => {
+            if interactive {
+                let _ = setpgid(Pid::from_raw(0), pgid.unwrap_or(Pid::from_raw(0)));
+            }
+            for sig in SHELL_SIGNALS {
+                let _ = unsafe { signal(sig, SigHandler::SigDfl) };
+            }
+
+            let placed = place_stdio(stdios.stdin, 0)
+                .and_then(|_| place_stdio(stdios.stdout, 1))
+                .and_then(|_| place_stdio(stdios.stderr, 2));
+            close_shell_fds(shell_ends);
+            let status = match placed {
+                Ok(()) => run_subshell(command.clone(), subshell_state),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    1
+                }
+            };
+
+            let _ = std::io::stdout().flush();
+            unsafe { libc::_exit(status) }
+        }
+    }
+}
+
+/// Closes `shell_ends` and everything else the shell opened for itself, which
+/// it marks close-on-exec. A subshell runs alongside the rest of its pipeline
+/// or the command it's substituted into, so if it held on to any of the pipes
+/// between them, e.g. the read end of its own stdout, whatever reads from the
+/// pipe would never see it end.
+fn close_shell_fds(shell_ends: &[RawFd]) {
+    let Ok(entries) = fs::read_dir("/dev/fd") else {
+        return;
+    };
+    let fds = entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<RawFd>().ok())
+        .collect::<Vec<_>>();
+    for fd in fds.into_iter().filter(|fd| *fd > 2) {
+        let cloexec = fcntl(fd, FcntlArg::F_GETFD)
+            .map(|flags| FdFlag::from_bits_truncate(flags).contains(FdFlag::FD_CLOEXEC))
+            .unwrap_or(false);
+        if cloexec || shell_ends.contains(&fd) {
+            let _ = close(fd);
+        }
+    }
+}
+
+/// Puts `io` on descriptor `fd` of this process.
+pub fn place_stdio(io: Io, fd: i32) -> Result<()> {
+    match io {
+        Io::Inherit => {}
+        Io::Null => {
+            let null = open("/dev/null", OFlag::O_RDWR, Mode::empty())?;
+            dup2(null, fd)?;
+        }
+        Io::File(file) => {
+            dup2(file.as_raw_fd(), fd)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The forking thread still thinks it's inside the parent's tokio runtime, so
+/// the subshell gets a fresh thread with a runtime of its own.
+fn run_subshell(command: Command, state: State) -> i32 {
+    thread::spawn(move || {
+        let runtime = match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                eprintln!("{}", e);
+                return 1;
+            }
+        };
+        let state = Arc::new(Mutex::new(state));
+
+        runtime.block_on(async {
+            match command.run(Io::Inherit, Io::Inherit, &state).await {
+                Ok(result) => result.status,
+                // `return` in a function ends a subshell inside it.
+                Err(e) => match e.downcast::<Return>() {
+                    Ok(Return { status }) => status,
+                    // So do `break` and `continue` in a loop around it.
+                    Err(e) if e.is::<LoopJump>() => 0,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        1
+                    }
+                },
+            }
+        })
+    })
+    .join()
+    .unwrap_or(1)
+}
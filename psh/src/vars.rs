@@ -0,0 +1,291 @@
+use std::{collections::HashMap, env};
+
+use anyhow::{bail, Result};
+
+/// Checks that `name` could be assigned to with `name=value`.
+pub fn check_name(name: &str) -> Result<()> {
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        bail!("{}: not a valid identifier", name);
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Variable {
+    pub value: String,
+    /// Whether child processes get it in their environment.
+    pub exported: bool,
+    pub readonly: bool,
+}
+
+/// The shell's variables, which are only passed on to children if exported.
+///
+/// Variables live in a stack of scopes. The outermost holds the globals, and
+/// `local` declares variables in the innermost.
+#[derive(Debug, Clone)]
+pub struct Variables {
+    scopes: Vec<HashMap<String, Variable>>,
+}
+
+impl Variables {
+    /// Starts with everything in psh's own environment, exported.
+    pub fn from_env() -> Self {
+        let globals = env::vars()
+            .map(|(name, value)| {
+                let var = Variable {
+                    value,
+                    exported: true,
+                    readonly: false,
+                };
+                (name, var)
+            })
+            .collect();
+
+        Self {
+            scopes: vec![globals],
+        }
+    }
+
+    fn find(&self, name: &str) -> Option<&Variable> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    /// The innermost definition of `name`, or a new global one.
+    fn entry(&mut self, name: &str) -> &mut Variable {
+        let index = self
+            .scopes
+            .iter()
+            .rposition(|scope| scope.contains_key(name))
+            .unwrap_or(0);
+
+        self.scopes[index].entry(name.to_owned()).or_default()
+    }
+
+    pub fn check_writable(&self, name: &str) -> Result<()> {
+        if self.find(name).is_some_and(|var| var.readonly) {
+            bail!("{}: readonly variable", name);
+        }
+
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.find(name).map(|var| var.value.as_str())
+    }
+
+    /// Sets a variable, keeping whether it's exported.
+    pub fn set(&mut self, name: &str, value: String) -> Result<()> {
+        self.check_writable(name)?;
+        self.entry(name).value = value;
+
+        Ok(())
+    }
+
+    /// Marks a variable as exported, setting it first if there's a `value`.
+    pub fn export(&mut self, name: &str, value: Option<String>) -> Result<()> {
+        if let Some(value) = value {
+            self.set(name, value)?;
+        }
+        self.entry(name).exported = true;
+
+        Ok(())
+    }
+
+    /// Stops passing a variable on to children without unsetting it.
+    pub fn unexport(&mut self, name: &str) {
+        if self.find(name).is_some() {
+            self.entry(name).exported = false;
+        }
+    }
+
+    /// Makes a variable read-only, setting it first if there's a `value`.
+    pub fn make_readonly(&mut self, name: &str, value: Option<String>) -> Result<()> {
+        if let Some(value) = value {
+            self.set(name, value)?;
+        }
+        self.entry(name).readonly = true;
+
+        Ok(())
+    }
+
+    /// Declares a variable in the innermost scope, hiding any outer one. It's
+    /// exported if the one it hides was, so children still get it.
+    pub fn declare_local(&mut self, name: &str, value: Option<String>) -> Result<()> {
+        let exported = self.find(name).is_some_and(|var| var.exported);
+        let scope = self.scopes.last_mut().unwrap();
+        if scope.get(name).is_some_and(|var| var.readonly) {
+            bail!("{}: readonly variable", name);
+        }
+        let var = scope.entry(name.to_owned()).or_insert_with(|| Variable {
+            exported,
+            ..Variable::default()
+        });
+        if let Some(value) = value {
+            var.value = value;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the innermost definition of a variable.
+    pub fn unset(&mut self, name: &str) -> Result<()> {
+        for scope in self.scopes.iter_mut().rev() {
+            if let Some(var) = scope.get(name) {
+                if var.readonly {
+                    bail!("{}: readonly variable", name);
+                }
+                scope.remove(name);
+                return Ok(());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pushes a scope holding exported copies of `vars`, for a builtin run
+    /// with `NAME=value` in front of it.
+    pub fn push_temporary(&mut self, vars: &[(String, String)]) -> Result<()> {
+        for (name, _) in vars {
+            self.check_writable(name)?;
+        }
+        let scope = vars
+            .iter()
+            .map(|(name, value)| {
+                let var = Variable {
+                    value: value.clone(),
+                    exported: true,
+                    readonly: false,
+                };
+                (name.clone(), var)
+            })
+            .collect();
+        self.scopes.push(scope);
+
+        Ok(())
+    }
+
+    /// Drops the innermost scope. The globals are never dropped.
+    pub fn pop_scope(&mut self) {
+        if self.scopes.len() > 1 {
+            self.scopes.pop();
+        }
+    }
+
+    /// Every visible variable, sorted by name.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Variable)> {
+        let mut visible = HashMap::new();
+        for scope in self.scopes.iter() {
+            visible.extend(scope.iter());
+        }
+        let mut visible = visible.into_iter().collect::<Vec<_>>();
+        visible.sort_by_key(|(name, _)| *name);
+
+        visible.into_iter()
+    }
+
+    /// The environment a child process gets.
+    pub fn exported(&self) -> Vec<(String, String)> {
+        self.iter()
+            .filter(|(_, var)| var.exported)
+            .map(|(name, var)| (name.clone(), var.value.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn empty() -> Variables {
+        Variables {
+            scopes: vec![HashMap::new()],
+        }
+    }
+
+    #[test]
+    fn local_hides_global_until_scope_ends() {
+        let mut vars = empty();
+        vars.set("x", "global".into()).unwrap();
+        vars.scopes.push(HashMap::new());
+        vars.declare_local("x", Some("local".into())).unwrap();
+        assert_eq!(vars.get("x"), Some("local"));
+
+        vars.set("x", "changed".into()).unwrap();
+        vars.pop_scope();
+        assert_eq!(vars.get("x"), Some("global"));
+    }
+
+    #[test]
+    fn local_without_value_is_empty() {
+        let mut vars = empty();
+        vars.set("x", "global".into()).unwrap();
+        vars.scopes.push(HashMap::new());
+        vars.declare_local("x", None).unwrap();
+        assert_eq!(vars.get("x"), Some(""));
+    }
+
+    #[test]
+    fn local_keeps_export_of_what_it_hides() {
+        let mut vars = empty();
+        vars.export("PATH", Some("/bin".into())).unwrap();
+        vars.set("plain", "1".into()).unwrap();
+        vars.scopes.push(HashMap::new());
+        vars.declare_local("PATH", Some("/usr/bin".into())).unwrap();
+        vars.declare_local("plain", Some("2".into())).unwrap();
+        vars.declare_local("new", Some("3".into())).unwrap();
+        assert_eq!(vars.exported(), [("PATH".into(), "/usr/bin".into())]);
+
+        vars.pop_scope();
+        assert_eq!(vars.exported(), [("PATH".into(), "/bin".into())]);
+    }
+
+    #[test]
+    fn export_and_unexport() {
+        let mut vars = empty();
+        vars.set("x", "1".into()).unwrap();
+        assert!(vars.exported().is_empty());
+        vars.export("x", None).unwrap();
+        assert_eq!(vars.exported(), [("x".into(), "1".into())]);
+        vars.set("x", "2".into()).unwrap();
+        assert_eq!(vars.exported(), [("x".into(), "2".into())]);
+        vars.unexport("x");
+        assert!(vars.exported().is_empty());
+        assert_eq!(vars.get("x"), Some("2"));
+    }
+
+    #[test]
+    fn unset_removes_innermost() {
+        let mut vars = empty();
+        vars.set("x", "global".into()).unwrap();
+        vars.scopes.push(HashMap::new());
+        vars.declare_local("x", Some("local".into())).unwrap();
+        vars.unset("x").unwrap();
+        assert_eq!(vars.get("x"), Some("global"));
+        vars.unset("x").unwrap();
+        assert_eq!(vars.get("x"), None);
+        vars.unset("x").unwrap();
+    }
+
+    #[test]
+    fn readonly_refuses_changes() {
+        let mut vars = empty();
+        vars.make_readonly("x", Some("1".into())).unwrap();
+        assert!(vars.set("x", "2".into()).is_err());
+        assert!(vars.unset("x").is_err());
+        assert_eq!(vars.get("x"), Some("1"));
+    }
+
+    #[test]
+    fn globals_outlive_pop_scope() {
+        let mut vars = empty();
+        vars.set("x", "1".into()).unwrap();
+        vars.pop_scope();
+        assert_eq!(vars.get("x"), Some("1"));
+    }
+}
@@ -0,0 +1,37 @@
+use std::{
+    io::Read,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Runs `script` with `psh -c` and returns what it printed, failing if it
+/// takes longer than a few seconds, as a stage left holding a pipe open would.
+/// Nothing else gets psh's stderr, which stages left behind could hold open.
+pub fn run(script: &str) -> String {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_psh"))
+        .args(["-c", script])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while child.try_wait().unwrap().is_none() {
+        if Instant::now() > deadline {
+            let _ = child.kill();
+            panic!("`{}` didn't finish", script);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    let mut stdout = String::new();
+    child
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_string(&mut stdout)
+        .unwrap();
+    stdout
+}
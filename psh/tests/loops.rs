@@ -0,0 +1,45 @@
+mod common;
+
+use common::run;
+
+#[test]
+fn break_and_continue() {
+    assert_eq!(run("for i in 1 2 3; do echo $i; break; done"), "1\n");
+    assert_eq!(
+        run("for i in 1 2 3; do if [ $i = 2 ]; then continue; fi; echo $i; done"),
+        "1\n3\n"
+    );
+    assert_eq!(
+        run("i=0; while true; do i=$((i + 1)); if [ $i = 3 ]; then break; fi; done; echo $i"),
+        "3\n"
+    );
+}
+
+#[test]
+fn break_and_continue_count_levels() {
+    assert_eq!(
+        run("for i in 1 2; do for j in a b; do echo $i$j; break 2; done; done"),
+        "1a\n"
+    );
+    assert_eq!(
+        run("for i in 1 2; do for j in a b; do echo $i$j; continue 2; done; done"),
+        "1a\n2a\n"
+    );
+}
+
+#[test]
+fn break_outside_loop_fails() {
+    assert_eq!(run("break; echo $?"), "1\n");
+    assert_eq!(run("for i in 1; do break 0; echo $?; done"), "1\n");
+}
+
+#[test]
+fn break_in_subshell_only_ends_it() {
+    assert_eq!(run("for i in 1 2; do (break); echo $i; done"), "1\n2\n");
+}
+
+#[test]
+fn failed_for_and_case_set_status() {
+    assert_eq!(run("for i in $(false); do :; done; echo $?"), "1\n");
+    assert_eq!(run("case $(false) in *) ;; esac; echo $?"), "1\n");
+}
@@ -0,0 +1,71 @@
+mod common;
+
+use common::run;
+
+#[test]
+fn subshell_stage_sees_reader_exit() {
+    assert_eq!(run("(yes) | head -1"), "y\n");
+    assert_eq!(run("{ yes; } | head -1"), "y\n");
+}
+
+#[test]
+fn function_stage_sees_reader_exit() {
+    assert_eq!(run("y() { yes; }; y | head -1"), "y\n");
+    assert_eq!(run("y() { yes; }; y | y | head -1"), "y\n");
+}
+
+#[test]
+fn function_stage_keeps_substitution_open() {
+    assert_eq!(run("f() { cat \"$1\"; }; f <(echo z) | cat"), "z\n");
+}
+
+#[test]
+fn process_substitution_reads_and_writes() {
+    assert_eq!(run("cat <(echo a) <(echo b)"), "a\nb\n");
+    assert_eq!(run("diff <(echo x) <(echo x) && echo same"), "same\n");
+    assert_eq!(run("echo hi > >(tr a-z A-Z)"), "HI\n");
+}
+
+#[test]
+fn sourced_script_stage_isnt_blocked_by_reader() {
+    let dir = std::env::temp_dir().join(format!("psh-source-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("big.psh");
+    std::fs::write(&script, "seq 1 100000\n").unwrap();
+
+    let output = run(&format!("source {} | wc -l", script.display()));
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(output.trim(), "100000");
+}
+
+#[test]
+fn sourced_script_takes_redirects() {
+    let dir = std::env::temp_dir().join(format!("psh-source-io-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("cat.psh");
+    let input = dir.join("in");
+    std::fs::write(&script, "cat\nnosuchcmd\n").unwrap();
+    std::fs::write(&input, "hello\n").unwrap();
+
+    let output = run(&format!(
+        "source {} < {} 2>&1",
+        script.display(),
+        input.display()
+    ));
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(output, "hello\npsh: nosuchcmd: command not found\n");
+}
+
+#[test]
+fn background_function_runs_alongside_shell() {
+    assert_eq!(
+        run("h() { sleep 1; echo slept; }; h & echo started; wait"),
+        "started\nslept\n"
+    );
+}
+
+#[test]
+fn background_builtin_leaves_shell_alone() {
+    assert_eq!(run("cd /tmp; cd / & wait; pwd"), "/tmp\n");
+    assert_eq!(run("x=1; x=2 & wait; echo $x"), "1\n");
+}
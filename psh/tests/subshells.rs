@@ -0,0 +1,26 @@
+mod common;
+
+use common::run;
+
+#[test]
+fn command_substitution_keeps_directory() {
+    assert_eq!(run("cd /; echo $(cd /tmp; pwd); pwd"), "/tmp\n/\n");
+}
+
+#[test]
+fn command_substitution_keeps_variables() {
+    assert_eq!(run("x=1; echo $(x=5; echo $x); echo $x"), "5\n1\n");
+}
+
+#[test]
+fn exit_in_command_substitution_leaves_shell_running() {
+    assert_eq!(run("x=$(exit 3); echo after"), "after\n");
+}
+
+#[test]
+fn return_in_command_substitution_ends_only_it() {
+    assert_eq!(
+        run("f() { x=$(return 3); echo in; }; f 2>&1"),
+        "psh: Error running subcommand\nin\n"
+    );
+}
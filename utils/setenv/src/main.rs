@@ -1,13 +1,8 @@
-use std::{env, path::PathBuf, process::exit};
+use std::{env, process::exit};
 
 use anyhow::Result;
 use protos::{create_channel, env_client::EnvClient, sock_path_from_env, EnvVar};
-use tokio::net::UnixStream;
-use tonic::{
-    transport::{Endpoint, Uri},
-    Request,
-};
-use tower::service_fn;
+use tonic::Request;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -17,7 +12,7 @@ async fn main() -> Result<()> {
     }
     let mut args = args.skip(1);
     let key = args.next().unwrap();
-    let value = args.into_iter().collect::<Vec<_>>();
+    let value = args.collect::<Vec<_>>();
     let value = value.join(" ");
     if !sock_path_from_env().map(|p| p.exists()).unwrap_or(false) {
         eprintln!("Couldn't find a service socket. Are you running from within psh?");
@@ -29,7 +24,11 @@ async fn main() -> Result<()> {
 
     let request = Request::new(EnvVar { key, value });
 
-    client.set_env(request).await?;
+    let resp = client.set_env(request).await?.into_inner();
+    if !resp.accepted {
+        eprintln!("setenv: {}", resp.reason);
+        exit(1);
+    }
 
     Ok(())
 }